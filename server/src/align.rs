@@ -0,0 +1,443 @@
+use {
+    image::{imageops::FilterType, RgbImage},
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::collections::{HashMap, HashSet},
+};
+
+/// Side length of the luma grid a fingerprint is reduced to.
+const GRID_SIZE: u32 = 16;
+
+/// Mean per-cell luma distance under which an out-of-order page counts as
+/// the same page moved elsewhere.
+const MOVE_THRESHOLD: f64 = 2.;
+
+/// Perceptual hashes further apart than this (in bits) are not even
+/// considered as move candidates.
+const MOVE_SHORTLIST_BITS: u32 = 10;
+
+/// Per-page fingerprint computed once from the full-size render and cached
+/// between runs (see `FingerprintCache`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageFingerprint {
+    /// Render dimensions; pages with different aspect ratios never match.
+    pub dimensions: (u32, u32),
+    /// `GRID_SIZE`² downscaled luma values.
+    pub cells: Vec<u8>,
+    /// 64-bit average hash: one bit per 8x8 cell, set when brighter than
+    /// the mean.
+    pub phash: u64,
+    /// Exact hash over the full render's pixels: the leading 64 bits of
+    /// their SHA-256, which unlike `DefaultHasher` stays the same across
+    /// Rust releases, so cached fingerprints keep matching fresh ones.
+    pub hash: u64,
+}
+
+impl PageFingerprint {
+    pub fn from_image(img: &RgbImage) -> Self {
+        let (width, height) = img.dimensions();
+        let digest = Sha256::new()
+            .chain_update(width.to_le_bytes())
+            .chain_update(height.to_le_bytes())
+            .chain_update(img.as_raw())
+            .finalize();
+        let luma = image::DynamicImage::ImageRgb8(img.clone()).into_luma8();
+        let cells =
+            image::imageops::resize(&luma, GRID_SIZE, GRID_SIZE, FilterType::Triangle).into_raw();
+        let small = image::imageops::resize(&luma, 8, 8, FilterType::Triangle).into_raw();
+        let mean = small.iter().map(|v| *v as u32).sum::<u32>() / small.len() as u32;
+        let phash = small
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| **v as u32 > mean)
+            .fold(0u64, |acc, (bit, _)| acc | 1 << bit);
+        PageFingerprint {
+            dimensions: img.dimensions(),
+            cells,
+            phash,
+            hash: u64::from_le_bytes(digest[..8].try_into().unwrap()),
+        }
+    }
+
+    /// Mean absolute luma difference per cell (0-255), `None` when the
+    /// pages cannot be compared at all.
+    pub fn distance(&self, other: &PageFingerprint) -> Option<f64> {
+        if self.dimensions != other.dimensions {
+            return None;
+        }
+        if self.hash == other.hash {
+            return Some(0.);
+        }
+        let total: u32 = self
+            .cells
+            .iter()
+            .zip(other.cells.iter())
+            .map(|(a, b)| a.abs_diff(*b) as u32)
+            .sum();
+        Some(total as f64 / self.cells.len().max(1) as f64)
+    }
+
+    /// Cost of aligning the two pages with each other. Always below the
+    /// cost of an insertion plus a deletion, so pages at matching positions
+    /// pair up unless shifting saves more elsewhere.
+    fn match_cost(&self, other: &PageFingerprint) -> f64 {
+        match self.distance(other) {
+            Some(d) => (d / 16.).min(1.5),
+            None => f64::INFINITY,
+        }
+    }
+}
+
+/// Where a page of the new document came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageMatch {
+    /// Paired in order with this page of the old document.
+    Aligned(u16),
+    /// Same content as this old page, but out of order.
+    Moved(u16),
+    /// No counterpart in the old document.
+    Inserted,
+}
+
+#[derive(Debug)]
+pub struct Alignment {
+    /// One entry per page of the new document.
+    pub pages: Vec<PageMatch>,
+    /// Old pages that have no counterpart in the new document.
+    pub deleted: Vec<u16>,
+}
+
+/// Align `new` onto `old` in order. Common leading and trailing pages are
+/// matched by hash first, and within the changed middle pages whose content
+/// is unique to both documents serve as anchors, so only the stretches
+/// between anchors go through the quadratic edit-distance pass. For the
+/// usual "edited a few pages" case this keeps the whole alignment close to
+/// linear, even when the first and last pages both changed.
+pub fn align(new: &[PageFingerprint], old: &[PageFingerprint]) -> Alignment {
+    let prefix = new
+        .iter()
+        .zip(old.iter())
+        .take_while(|(a, b)| a.hash == b.hash)
+        .count();
+    let suffix = new[prefix..]
+        .iter()
+        .rev()
+        .zip(old[prefix..].iter().rev())
+        .take_while(|(a, b)| a.hash == b.hash)
+        .count();
+
+    let mut pages: Vec<PageMatch> = (0..prefix).map(|i| PageMatch::Aligned(i as u16)).collect();
+    let mut deleted = Vec::new();
+
+    let new_mid = &new[prefix..new.len() - suffix];
+    let old_mid = &old[prefix..old.len() - suffix];
+    for step in anchored_alignment(new_mid, old_mid) {
+        match step {
+            Step::Match(j) => pages.push(PageMatch::Aligned((prefix + j) as u16)),
+            Step::Insert => pages.push(PageMatch::Inserted),
+            Step::Delete(j) => deleted.push((prefix + j) as u16),
+        }
+    }
+
+    let offset_new = new.len() - suffix;
+    let offset_old = old.len() - suffix;
+    pages.extend((0..suffix).map(|k| PageMatch::Aligned((offset_old + k) as u16)));
+    debug_assert_eq!(pages.len(), offset_new + suffix);
+
+    detect_moves(new, old, &mut pages, &mut deleted);
+    Alignment { pages, deleted }
+}
+
+/// Pages of `new` whose exact content also appears somewhere in `old`.
+pub fn shared_pages(new: &[PageFingerprint], old: &[PageFingerprint]) -> usize {
+    let old: HashSet<u64> = old.iter().map(|p| p.hash).collect();
+    new.iter().filter(|p| old.contains(&p.hash)).count()
+}
+
+/// Pair inserted pages with deleted pages that look the same.
+fn detect_moves(
+    new: &[PageFingerprint],
+    old: &[PageFingerprint],
+    pages: &mut [PageMatch],
+    deleted: &mut Vec<u16>,
+) {
+    for (i, page) in pages.iter_mut().enumerate() {
+        if *page != PageMatch::Inserted {
+            continue;
+        }
+        let best = deleted
+            .iter()
+            .enumerate()
+            .filter(|(_, j)| {
+                (new[i].phash ^ old[**j as usize].phash).count_ones() <= MOVE_SHORTLIST_BITS
+            })
+            .filter_map(|(k, j)| Some((k, *j, new[i].distance(&old[*j as usize])?)))
+            .filter(|(_, _, d)| *d <= MOVE_THRESHOLD)
+            .min_by(|a, b| a.2.total_cmp(&b.2));
+        if let Some((k, j, _)) = best {
+            *page = PageMatch::Moved(j);
+            deleted.remove(k);
+        }
+    }
+}
+
+/// One step of an edit alignment, carrying the old page index it consumes.
+enum Step {
+    Match(usize),
+    Insert,
+    Delete(usize),
+}
+
+impl Step {
+    /// The same step with the old page index shifted by `by`.
+    fn offset(self, by: usize) -> Step {
+        match self {
+            Step::Match(j) => Step::Match(j + by),
+            Step::Insert => Step::Insert,
+            Step::Delete(j) => Step::Delete(j + by),
+        }
+    }
+}
+
+/// `edit_alignment` of the stretches between `anchors`, which are matched
+/// as they are.
+fn anchored_alignment(new: &[PageFingerprint], old: &[PageFingerprint]) -> Vec<Step> {
+    let mut steps = Vec::with_capacity(new.len().max(old.len()));
+    let (mut i, mut j) = (0, 0);
+    for (anchor_i, anchor_j) in anchors(new, old)
+        .into_iter()
+        .chain([(new.len(), old.len())])
+    {
+        let stretch = edit_alignment(&new[i..anchor_i], &old[j..anchor_j]);
+        steps.extend(stretch.into_iter().map(|step| step.offset(j)));
+        if anchor_i < new.len() {
+            steps.push(Step::Match(anchor_j));
+        }
+        (i, j) = (anchor_i + 1, anchor_j + 1);
+    }
+    steps
+}
+
+/// Pairs of a new and an old page index whose content occurs exactly once
+/// in each document, as in patience diff: the longest run of them that is
+/// in the same order in both.
+fn anchors(new: &[PageFingerprint], old: &[PageFingerprint]) -> Vec<(usize, usize)> {
+    // Occurrences in `new` and `old`, and the position in `old`.
+    let mut counts: HashMap<u64, (usize, usize, usize)> = HashMap::new();
+    for page in new {
+        counts.entry(page.hash).or_default().0 += 1;
+    }
+    for (j, page) in old.iter().enumerate() {
+        let count = counts.entry(page.hash).or_default();
+        count.1 += 1;
+        count.2 = j;
+    }
+    let unique: Vec<(usize, usize)> = new
+        .iter()
+        .enumerate()
+        .filter_map(|(i, page)| match counts[&page.hash] {
+            (1, 1, j) => Some((i, j)),
+            _ => None,
+        })
+        .collect();
+    longest_increasing(&unique)
+}
+
+/// Longest subsequence of `pairs` (increasing in the first index) that is
+/// also increasing in the second, by patience sorting.
+fn longest_increasing(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // tails[l]: pair ending the run of length l + 1 with the smallest second index.
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous = vec![None; pairs.len()];
+    for (k, (_, j)) in pairs.iter().enumerate() {
+        let l = tails.partition_point(|t| pairs[*t].1 < *j);
+        if l > 0 {
+            previous[k] = Some(tails[l - 1]);
+        }
+        match tails.get_mut(l) {
+            Some(tail) => *tail = k,
+            None => tails.push(k),
+        }
+    }
+    let mut run = Vec::with_capacity(tails.len());
+    let mut next = tails.last().copied();
+    while let Some(k) = next {
+        run.push(pairs[k]);
+        next = previous[k];
+    }
+    run.reverse();
+    run
+}
+
+/// Minimum-cost alignment with unit insert/delete costs and
+/// `match_cost` for pairing two pages.
+fn edit_alignment(new: &[PageFingerprint], old: &[PageFingerprint]) -> Vec<Step> {
+    let (n, m) = (new.len(), old.len());
+    // cost[i][j] = cheapest alignment of new[i..] with old[j..]
+    let mut cost = vec![vec![0f64; m + 1]; n + 1];
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            cost[i][j] = match (i < n, j < m) {
+                (false, false) => 0.,
+                (true, false) => cost[i + 1][j] + 1.,
+                (false, true) => cost[i][j + 1] + 1.,
+                (true, true) => (new[i].match_cost(&old[j]) + cost[i + 1][j + 1])
+                    .min(cost[i + 1][j] + 1.)
+                    .min(cost[i][j + 1] + 1.),
+            };
+        }
+    }
+
+    let mut steps = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && cost[i][j] == new[i].match_cost(&old[j]) + cost[i + 1][j + 1] {
+            steps.push(Step::Match(j));
+            i += 1;
+            j += 1;
+        } else if i < n && cost[i][j] == cost[i + 1][j] + 1. {
+            steps.push(Step::Insert);
+            i += 1;
+        } else {
+            steps.push(Step::Delete(j));
+            j += 1;
+        }
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A page filled with a single luma value; equal `id`s are the same
+    /// content.
+    fn page(id: u64, luma: u8) -> PageFingerprint {
+        PageFingerprint {
+            dimensions: (100, 100),
+            cells: vec![luma; (GRID_SIZE * GRID_SIZE) as usize],
+            phash: 0,
+            hash: id,
+        }
+    }
+
+    fn pages(ids: &[u64]) -> Vec<PageFingerprint> {
+        ids.iter().map(|id| page(*id, (*id * 40) as u8)).collect()
+    }
+
+    #[test]
+    fn identical_documents_align_in_order() {
+        let doc = pages(&[1, 2, 3]);
+        let alignment = align(&doc, &doc);
+        assert_eq!(
+            alignment.pages,
+            vec![
+                PageMatch::Aligned(0),
+                PageMatch::Aligned(1),
+                PageMatch::Aligned(2)
+            ]
+        );
+        assert!(alignment.deleted.is_empty());
+    }
+
+    #[test]
+    fn inserted_page() {
+        let alignment = align(&pages(&[1, 5, 2]), &pages(&[1, 2]));
+        assert_eq!(
+            alignment.pages,
+            vec![
+                PageMatch::Aligned(0),
+                PageMatch::Inserted,
+                PageMatch::Aligned(1)
+            ]
+        );
+        assert!(alignment.deleted.is_empty());
+    }
+
+    #[test]
+    fn deleted_page() {
+        let alignment = align(&pages(&[1, 2]), &pages(&[1, 5, 2]));
+        assert_eq!(
+            alignment.pages,
+            vec![PageMatch::Aligned(0), PageMatch::Aligned(2)]
+        );
+        assert_eq!(alignment.deleted, vec![1]);
+    }
+
+    #[test]
+    fn moved_page() {
+        let alignment = align(&pages(&[2, 3, 4, 1]), &pages(&[1, 2, 3, 4]));
+        assert_eq!(
+            alignment.pages,
+            vec![
+                PageMatch::Aligned(1),
+                PageMatch::Aligned(2),
+                PageMatch::Aligned(3),
+                PageMatch::Moved(0)
+            ]
+        );
+        assert!(alignment.deleted.is_empty());
+    }
+
+    #[test]
+    fn edited_page_between_common_prefix_and_suffix() {
+        let alignment = align(&pages(&[1, 2, 5, 3, 4]), &pages(&[1, 2, 6, 3, 4]));
+        assert_eq!(
+            alignment.pages,
+            (0..5).map(PageMatch::Aligned).collect::<Vec<_>>()
+        );
+        assert!(alignment.deleted.is_empty());
+    }
+
+    #[test]
+    fn changed_first_and_last_pages_align_through_anchors() {
+        let new: Vec<u64> = [100].into_iter().chain(2..=50).chain([200]).collect();
+        let old: Vec<u64> = (1..=50).collect();
+        let alignment = align(&pages(&new), &pages(&old));
+        let expected: Vec<PageMatch> = (0..50)
+            .map(PageMatch::Aligned)
+            .chain([PageMatch::Inserted])
+            .collect();
+        assert_eq!(alignment.pages, expected);
+        assert!(alignment.deleted.is_empty());
+    }
+
+    #[test]
+    fn anchors_are_unique_pages_in_common_order() {
+        // 2 repeats in `new`, 4 is out of order.
+        assert_eq!(
+            anchors(&pages(&[1, 2, 2, 3, 4]), &pages(&[4, 1, 2, 3])),
+            vec![(0, 1), (3, 3)]
+        );
+        assert!(anchors(&pages(&[1, 1]), &pages(&[1])).is_empty());
+    }
+
+    #[test]
+    fn pages_of_different_size_never_pair() {
+        let mut new = pages(&[1]);
+        new[0].dimensions = (100, 200);
+        let alignment = align(&new, &pages(&[2]));
+        assert_eq!(alignment.pages, vec![PageMatch::Inserted]);
+        assert_eq!(alignment.deleted, vec![0]);
+    }
+
+    #[test]
+    fn empty_documents() {
+        let alignment = align(&[], &[]);
+        assert!(alignment.pages.is_empty() && alignment.deleted.is_empty());
+
+        let alignment = align(&pages(&[1, 2]), &[]);
+        assert_eq!(alignment.pages, vec![PageMatch::Inserted; 2]);
+        assert!(alignment.deleted.is_empty());
+
+        let alignment = align(&[], &pages(&[1, 2]));
+        assert!(alignment.pages.is_empty());
+        assert_eq!(alignment.deleted, vec![0, 1]);
+    }
+
+    #[test]
+    fn shared_pages_ignores_order() {
+        assert_eq!(shared_pages(&pages(&[3, 1, 7]), &pages(&[1, 2, 3])), 2);
+        assert_eq!(shared_pages(&[], &pages(&[1])), 0);
+    }
+}
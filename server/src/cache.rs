@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::align::PageFingerprint;
+use crate::persist::PendingWrite;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Entries not looked up for this long are dropped on save.
+const MAX_IDLE_SECONDS: i64 = 30 * SECONDS_PER_DAY;
+
+/// Bumped whenever `PageFingerprint` is computed differently; caches
+/// written with another version are discarded.
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile<E> {
+    version: u32,
+    entries: E,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    last_used: i64,
+    pages: Vec<PageFingerprint>,
+}
+
+/// Per-page fingerprints of every PDF seen, persisted as JSON between runs
+/// and keyed by the SHA-256 of the file's bytes. Keying by content rather
+/// than path means the `last_path` copy of a document hits the entry that
+/// was written while it was still the current version.
+#[derive(Debug)]
+pub struct FingerprintCache {
+    path: PathBuf,
+    entries: HashMap<String, CacheEntry>,
+    /// Keys inserted since the last `take_inserted` or `pending_write`.
+    inserted: Vec<String>,
+    /// Keys looked up since the last `take_used` or `pending_write`.
+    used: Vec<String>,
+    dirty: bool,
+}
+
+impl FingerprintCache {
+    /// Load the cache at `path`, starting empty if it is missing or
+    /// unreadable.
+    pub async fn load(path: PathBuf) -> Self {
+        let entries = match tokio::fs::read(&path).await {
+            Ok(bytes) => match serde_json::from_slice::<CacheFile<_>>(&bytes) {
+                Ok(file) if file.version == CACHE_VERSION => file.entries,
+                Ok(_) => {
+                    tracing::info!(path = %path.display(), "discarding outdated fingerprint cache");
+                    HashMap::new()
+                }
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), "discarding fingerprint cache: {}", e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        FingerprintCache {
+            path,
+            entries,
+            inserted: Vec::new(),
+            used: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    pub fn get(&mut self, file_hash: &str) -> Option<Vec<PageFingerprint>> {
+        if !self.touch(file_hash) {
+            return None;
+        }
+        self.used.push(file_hash.to_string());
+        Some(self.entries[file_hash].pages.clone())
+    }
+
+    /// Mark the entry for `file_hash` as used now, e.g. after a worker
+    /// process looked it up. Returns whether there is one.
+    pub fn touch(&mut self, file_hash: &str) -> bool {
+        let Some(entry) = self.entries.get_mut(file_hash) else {
+            return false;
+        };
+        let now = Utc::now().timestamp();
+        // Eviction works in units of days; a lookup alone only warrants
+        // rewriting the file once per entry and day.
+        if now / SECONDS_PER_DAY != entry.last_used / SECONDS_PER_DAY {
+            self.dirty = true;
+        }
+        entry.last_used = now;
+        true
+    }
+
+    pub fn insert(&mut self, file_hash: String, pages: Vec<PageFingerprint>) {
+        self.inserted.push(file_hash.clone());
+        self.entries.insert(
+            file_hash,
+            CacheEntry {
+                last_used: Utc::now().timestamp(),
+                pages,
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Add fingerprints handed over by the plugin to a worker process. Unlike
+    /// `insert`, they are not passed back through `take_inserted`.
+    pub fn seed(&mut self, file_hash: String, pages: Vec<PageFingerprint>) {
+        self.entries.entry(file_hash).or_insert(CacheEntry {
+            last_used: Utc::now().timestamp(),
+            pages,
+        });
+    }
+
+    /// Keys looked up since the last call, so the plugin can keep entries
+    /// a worker process uses from being evicted.
+    pub fn take_used(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.used)
+    }
+
+    /// Entries inserted since the last call, for handing fingerprints
+    /// computed in a worker process back to the plugin.
+    pub fn take_inserted(&mut self) -> Vec<(String, Vec<PageFingerprint>)> {
+        std::mem::take(&mut self.inserted)
+            .into_iter()
+            .filter_map(|key| {
+                let pages = self.entries.get(&key)?.pages.clone();
+                Some((key, pages))
+            })
+            .collect()
+    }
+
+    /// Drop idle entries and, if anything changed since the last call,
+    /// serialize the cache for writing once the lock is released.
+    pub fn pending_write(&mut self) -> io::Result<Option<PendingWrite>> {
+        // Whatever was inserted or used is persisted now, so there is
+        // nothing left to hand on.
+        self.inserted.clear();
+        self.used.clear();
+        if !self.dirty {
+            return Ok(None);
+        }
+        let cutoff = Utc::now().timestamp() - MAX_IDLE_SECONDS;
+        self.entries.retain(|_, entry| entry.last_used >= cutoff);
+        let file = CacheFile {
+            version: CACHE_VERSION,
+            entries: &self.entries,
+        };
+        let bytes = serde_json::to_vec(&file)?;
+        self.dirty = false;
+        Ok(Some(PendingWrite::new(self.path.clone(), bytes)))
+    }
+
+    /// Mark the cache as changed again after its `PendingWrite` failed.
+    pub fn write_failed(&mut self) {
+        self.dirty = true;
+    }
+}
+
+/// Hex SHA-256 of the file at `path`.
+pub fn file_hash(path: &Path) -> io::Result<String> {
+    Ok(hex_sha256(&std::fs::read(path)?))
+}
+
+/// `file_hash` for async callers.
+pub async fn file_hash_async(path: &Path) -> io::Result<String> {
+    Ok(hex_sha256(&tokio::fs::read(path).await?))
+}
+
+pub fn hex_sha256(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> FingerprintCache {
+        FingerprintCache {
+            path: std::env::temp_dir().join("fingerprints-test.json"),
+            entries: HashMap::new(),
+            inserted: Vec::new(),
+            used: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    fn pages() -> Vec<PageFingerprint> {
+        vec![PageFingerprint {
+            dimensions: (1, 1),
+            cells: vec![0],
+            phash: 0,
+            hash: 0,
+        }]
+    }
+
+    fn keys(mut keys: Vec<String>) -> Vec<String> {
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn idle_entries_are_evicted_on_save() {
+        let mut cache = cache();
+        cache.insert("idle".into(), pages());
+        cache.insert("recent".into(), pages());
+        let now = Utc::now().timestamp();
+        cache.entries.get_mut("idle").unwrap().last_used = now - MAX_IDLE_SECONDS - 1;
+        cache.entries.get_mut("recent").unwrap().last_used = now - MAX_IDLE_SECONDS + 60;
+        assert!(cache.pending_write().unwrap().is_some());
+        assert!(!cache.entries.contains_key("idle"));
+        assert!(cache.entries.contains_key("recent"));
+        assert!(cache.pending_write().unwrap().is_none(), "nothing changed");
+    }
+
+    #[test]
+    fn touch_refreshes_last_used() {
+        let mut cache = cache();
+        assert!(!cache.touch("missing"));
+
+        cache.insert("a".into(), pages());
+        cache.pending_write().unwrap();
+        let two_days_ago = Utc::now().timestamp() - 2 * SECONDS_PER_DAY;
+        cache.entries.get_mut("a").unwrap().last_used = two_days_ago;
+        assert!(cache.touch("a"));
+        assert!(cache.entries["a"].last_used > two_days_ago);
+        assert!(cache.dirty, "moved to a new day");
+    }
+
+    #[test]
+    fn lookups_on_the_same_day_do_not_rewrite_the_cache() {
+        let mut cache = cache();
+        cache.insert("a".into(), pages());
+        cache.pending_write().unwrap();
+        assert!(cache.get("a").is_some());
+        assert!(!cache.dirty);
+    }
+
+    #[test]
+    fn inserted_and_used_keys_are_handed_on_once() {
+        let mut cache = cache();
+        cache.insert("a".into(), pages());
+        cache.insert("b".into(), pages());
+        cache.seed("seeded".into(), pages());
+        let inserted = cache.take_inserted().into_iter().map(|(key, _)| key);
+        assert_eq!(keys(inserted.collect()), vec!["a", "b"]);
+        assert!(cache.take_inserted().is_empty());
+
+        assert!(cache.get("seeded").is_some());
+        assert!(cache.get("missing").is_none());
+        assert_eq!(cache.take_used(), vec!["seeded"]);
+        assert!(cache.take_used().is_empty());
+    }
+
+    #[test]
+    fn saving_clears_what_would_be_handed_on() {
+        let mut cache = cache();
+        cache.insert("a".into(), pages());
+        cache.get("a");
+        cache.pending_write().unwrap();
+        assert!(cache.take_inserted().is_empty());
+        assert!(cache.take_used().is_empty());
+    }
+}
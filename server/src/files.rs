@@ -1,15 +1,30 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{FileType, Metadata};
+use std::fs::FileType;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
-use chrono::Utc;
-use pdfium_render::prelude::Pdfium;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
 use tokio::fs::{copy, create_dir_all, metadata, read_dir};
 
-use crate::pdf::{Comparison, PDFComparison, PDFComparisonError, PDFEditor, PDFEditorError};
+use crate::align::{shared_pages, PageFingerprint};
+use crate::cache::{file_hash_async, FingerprintCache};
+use crate::filter::PathFilter;
+use crate::loader::PdfiumLoader;
+use crate::pdf::{PDFComparisonError, PDFEditorError};
+use crate::persist::write_pending;
+use crate::pool::WorkerPool;
+use crate::settle::{Settler, Snapshot};
+use crate::sidecar::{DiffSidecar, DocumentChange};
+use crate::state::{FileState, StateIndex};
+use crate::stats::DiffStats;
+use crate::versions::{store_object, Revision, VersionStore};
+use crate::watch::{LocationWatcher, WatchedChanges};
+use crate::worker::{run_job, DiffJob, DiffSettings, Job, Supervisor};
+use crate::Location;
 
 #[derive(Debug, thiserror::Error)]
 pub enum FileManagerError {
@@ -19,6 +34,20 @@ pub enum FileManagerError {
     Compare(#[from] PDFComparisonError),
     #[error("pdf edit: {0}")]
     Edit(#[from] PDFEditorError),
+    #[error("no revision of {0} at that time")]
+    NoRevision(PathBuf),
+    #[error("{0} did not change between those times")]
+    Unchanged(PathBuf),
+    #[error("filter: {0}")]
+    Filter(#[from] globset::Error),
+    #[error("pdf worker: {0}")]
+    Worker(String),
+    #[error("pdfium is not available")]
+    PdfiumUnavailable,
+    /// The worker crashed or hung on the file; it is skipped until it
+    /// changes.
+    #[error("quarantined: {0}")]
+    Quarantined(String),
 }
 
 enum FileTypeEnum {
@@ -36,57 +65,864 @@ impl From<FileType> for FileTypeEnum {
     }
 }
 
-impl From<&Metadata> for FileTypeEnum {
-    fn from(value: &Metadata) -> Self {
-        if value.is_dir() {
-            Self::Dir
-        } else {
-            Self::File
-        }
-    }
+/// When the first scan of a location snapshots every file into `last_path`
+/// without generating diffs, so adding a location does not put every
+/// existing document on the timeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BaselineMode {
+    /// Only if `last_path` is missing or empty, i.e. the location is new.
+    #[default]
+    Auto,
+    /// On every start, accepting whatever changed while the plugin was not
+    /// running.
+    Always,
+    /// Never; every file without a snapshot is diffed as a new document.
+    Never,
+}
+
+/// A file deleted in this scan, as a candidate for where a new file came
+/// from.
+struct DeletedFile {
+    current: PathBuf,
+    snapshot: PathBuf,
+    /// Content hash of the snapshot, if it could be read.
+    hash: Option<String>,
+}
+
+/// A new file with the same content as a file deleted in the same scan.
+struct Rename {
+    current: PathBuf,
+    state: FileState,
+    /// The deleted file in `current_path`.
+    from: PathBuf,
+    /// Its snapshot in `last_path`.
+    snapshot: PathBuf,
 }
 
+/// How often queued watcher events are picked up.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Default time between full rescans of a watched location.
+const WATCHED_RESCAN_INTERVAL: Duration = Duration::from_secs(600);
+/// Default time between full rescans of a location without a watcher.
+const UNWATCHED_RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest a deletion is held back waiting for a new file to settle.
+const MAX_DELETION_HOLD: Duration = Duration::from_secs(600);
+
 pub struct FileManager {
     pub current_path: PathBuf,
     pub last_path: PathBuf,
     pub diff_path: PathBuf,
-    pdf_comparison: PDFComparison,
-    pdf_editor: PDFEditor,
+    archive_path: Option<PathBuf>,
+    filter: PathFilter,
+    pdfium: Arc<PdfiumLoader>,
+    fingerprint_cache: Arc<Mutex<FingerprintCache>>,
+    settings: DiffSettings,
+    pool: Arc<WorkerPool>,
+    supervisor: Option<Arc<Supervisor>>,
+    state: Arc<Mutex<StateIndex>>,
+    watcher: Option<LocationWatcher>,
+    rescan_interval: Duration,
+    last_scan: Mutex<Option<Instant>>,
+    settler: Mutex<Settler>,
+    /// Deleted files not recorded yet, by current path, with their
+    /// snapshot and when they were first seen gone.
+    held_deletions: Mutex<HashMap<PathBuf, (PathBuf, Instant)>>,
+    /// Changed files left alone because pdfium was unavailable.
+    awaiting_pdfium: Mutex<HashSet<PathBuf>>,
+    versions: Mutex<VersionStore>,
+    /// Taken by the first full scan.
+    baseline: Mutex<Option<BaselineMode>>,
 }
 
 impl FileManager {
-    pub fn new(
-        pdfium: Arc<Pdfium>,
-        current_path: PathBuf,
-        last_path: PathBuf,
-        diff_path: PathBuf,
-    ) -> Self {
-        FileManager {
-            diff_path,
-            current_path,
-            last_path,
-            pdf_comparison: PDFComparison::new(pdfium.clone()),
-            pdf_editor: PDFEditor::new(pdfium),
+    pub async fn new(
+        pdfium: Arc<PdfiumLoader>,
+        location: &Location,
+        fingerprint_cache: Arc<Mutex<FingerprintCache>>,
+        state: Arc<Mutex<StateIndex>>,
+        pool: Arc<WorkerPool>,
+        supervisor: Option<Arc<Supervisor>>,
+    ) -> Result<Self, FileManagerError> {
+        let filter = PathFilter::new(&location.filter)?;
+        let versions = VersionStore::open(
+            location
+                .versions_path
+                .clone()
+                .unwrap_or_else(|| location.last_path.join(".versions")),
+        )
+        .await?;
+        let watcher = if location.watch {
+            match LocationWatcher::new(&location.current_path) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    tracing::warn!(
+                        path = %location.current_path.display(),
+                        "unable to watch, falling back to polling: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let rescan_interval = match (location.rescan_interval, &watcher) {
+            (Some(secs), _) => Duration::from_secs(secs),
+            (None, Some(_)) => WATCHED_RESCAN_INTERVAL,
+            (None, None) => UNWATCHED_RESCAN_INTERVAL,
+        };
+        Ok(FileManager {
+            diff_path: location.diff_path.clone(),
+            current_path: location.current_path.clone(),
+            last_path: location.last_path.clone(),
+            archive_path: location.archive_path.clone(),
+            filter,
+            pdfium,
+            fingerprint_cache,
+            settings: DiffSettings {
+                comparison: location.comparison.clone(),
+                layout: location.diff_layout,
+                context: location.context,
+            },
+            pool,
+            supervisor,
+            state,
+            watcher,
+            rescan_interval,
+            last_scan: Mutex::new(None),
+            settler: Mutex::new(Settler::new(
+                Duration::from_secs(location.settle_time),
+                location.max_retries,
+            )),
+            held_deletions: Mutex::new(HashMap::new()),
+            awaiting_pdfium: Mutex::new(HashSet::new()),
+            versions: Mutex::new(versions),
+            baseline: Mutex::new(Some(location.baseline)),
+        })
+    }
+
+    /// How long until `poll` should be called again.
+    pub fn poll_interval(&self) -> Duration {
+        if self.watcher.is_some()
+            || self.settler().is_waiting()
+            || !self.held_deletions().is_empty()
+            || !self.awaiting_pdfium().is_empty()
+        {
+            return WATCH_POLL_INTERVAL;
+        }
+        self.rescan_interval
+    }
+
+    /// Process the files the watcher reported, those still settling or
+    /// waiting for a retry or for pdfium to be bound, and held back
+    /// deletions, or rescan the whole location when a rescan is
+    /// due or watcher events were lost.
+    pub async fn poll(
+        &self,
+    ) -> Result<HashMap<PathBuf, Result<PathBuf, FileManagerError>>, FileManagerError> {
+        let rescan_due = self
+            .last_scan
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_none_or(|t| t.elapsed() >= self.rescan_interval);
+        match (
+            rescan_due,
+            self.watcher.as_ref().map(LocationWatcher::drain),
+        ) {
+            (true, _) | (_, Some(WatchedChanges::Overflowed)) => self.update().await,
+            (false, changes) => {
+                let mut paths = match changes {
+                    Some(WatchedChanges::Paths(paths)) => paths,
+                    _ => Vec::new(),
+                };
+                paths.extend(self.settler().waiting());
+                let resumed = self.pdfium_resumed();
+                paths.extend(resumed.iter().cloned());
+                if paths.is_empty() && self.held_deletions().is_empty() {
+                    return Ok(HashMap::new());
+                }
+                let (pdf_files, deleted) = match self.watched_pdf_files(paths).await {
+                    Ok(v) => v,
+                    Err(error) => {
+                        // The watcher's events are used up; have the next
+                        // poll rescan the location instead.
+                        *self.last_scan.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                        return Err(error);
+                    }
+                };
+                self.awaiting_pdfium()
+                    .retain(|path| !resumed.contains(path));
+                self.process(pdf_files, deleted).await
+            }
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, StateIndex> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn settler(&self) -> MutexGuard<'_, Settler> {
+        self.settler.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn held_deletions(&self) -> MutexGuard<'_, HashMap<PathBuf, (PathBuf, Instant)>> {
+        self.held_deletions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn awaiting_pdfium(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+        self.awaiting_pdfium
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The files that were waiting for pdfium, once it is bound. They stay
+    /// in `awaiting_pdfium` until `poll` has listed them.
+    fn pdfium_resumed(&self) -> Vec<PathBuf> {
+        if self.pdfium.get().is_none() {
+            return Vec::new();
         }
+        self.awaiting_pdfium().iter().cloned().collect()
     }
 
+    fn versions(&self) -> MutexGuard<'_, VersionStore> {
+        self.versions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn fingerprint_cache(&self) -> MutexGuard<'_, FingerprintCache> {
+        self.fingerprint_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Scan the whole location for changed and deleted files.
     pub async fn update(
         &self,
     ) -> Result<HashMap<PathBuf, Result<PathBuf, FileManagerError>>, FileManagerError> {
-        let updated_files = Box::pin(FileManager::find_updated_files(
-            self.current_path.clone(),
-            self.last_path.clone(),
-        ))
-        .await?
-        .into_iter()
-        .collect::<HashMap<_, _>>();
-        let comparisons = self.generate_comparisons(&updated_files);
-        let updated_pdfs = self.generate_updated_pdfs(comparisons);
+        *self.last_scan.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        let pdf_files = self
+            .find_pdf_files(self.current_path.clone(), self.last_path.clone())
+            .await?;
+        if self.take_baseline().await? {
+            self.adopt(pdf_files).await?;
+            // Only now, so a failed attempt is repeated on the next scan
+            // instead of diffing whatever was not adopted yet.
+            *self.baseline.lock().unwrap_or_else(|e| e.into_inner()) = None;
+            return Ok(HashMap::new());
+        }
+        let deleted = self.find_deleted_files(self.last_path.clone()).await?;
+        self.process(pdf_files, deleted).await
+    }
+
+    /// Whether this scan should only take a baseline. Stays true until a
+    /// baseline was taken.
+    async fn take_baseline(&self) -> io::Result<bool> {
+        let mode = *self.baseline.lock().unwrap_or_else(|e| e.into_inner());
+        let baseline = match mode {
+            Some(BaselineMode::Always) => true,
+            Some(BaselineMode::Auto) => match read_dir(&self.last_path).await {
+                Ok(mut entries) => entries.next_entry().await?.is_none(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+                Err(e) => return Err(e),
+            },
+            Some(BaselineMode::Never) | None => false,
+        };
+        // Once snapshots exist `last_path` is no longer empty, so an
+        // interrupted baseline has to be continued regardless.
+        *self.baseline.lock().unwrap_or_else(|e| e.into_inner()) =
+            baseline.then_some(BaselineMode::Always);
+        Ok(baseline)
+    }
+
+    /// Snapshot `pdf_files` into `last_path` and the version store as they
+    /// are, without generating diffs. Diffs start with the next change.
+    /// Files that are still being written or cannot be read are skipped
+    /// and show up as new documents later.
+    async fn adopt(&self, pdf_files: Vec<(PathBuf, PathBuf)>) -> Result<(), FileManagerError> {
+        for (current, last) in pdf_files {
+            if let Err(e) = self.adopt_file(&current, &last).await {
+                tracing::warn!(path = %current.display(), "unable to take baseline: {}", e);
+            }
+        }
+        tracing::info!(path = %self.current_path.display(), "took baseline");
+        self.save_indexes().await?;
+        Ok(())
+    }
+
+    async fn adopt_file(&self, current: &Path, last: &Path) -> Result<(), FileManagerError> {
+        let meta = metadata(current).await?;
+        let (size, modified) = (meta.len(), meta.modified().ok());
+        let known = self.state().get(current);
+        // Adopted by an earlier, interrupted attempt.
+        if known.is_some_and(|k| k.matches_metadata(size, modified)) {
+            return Ok(());
+        }
+        if !self.settler().ready(current, Snapshot { size, modified }) {
+            return Ok(());
+        }
+        let state = FileState {
+            size,
+            modified,
+            hash: file_hash_async(current).await?,
+            quarantined: false,
+        };
+        if let Some(parent) = last.parent() {
+            create_dir_all(parent).await?;
+        }
+        copy(current, last).await?;
+        let document = current.strip_prefix(&self.current_path).unwrap_or(current);
+        let observed = state
+            .modified
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(Utc::now);
+        self.record_version(document, current, &state.hash, observed)
+            .await?;
+        self.state().insert(current, state);
+        Ok(())
+    }
+
+    /// Compare and diff those of `pdf_files` that changed, and record the
+    /// `deleted` ones and renames among the two.
+    async fn process(
+        &self,
+        pdf_files: Vec<(PathBuf, PathBuf)>,
+        deleted: Vec<(PathBuf, PathBuf)>,
+    ) -> Result<HashMap<PathBuf, Result<PathBuf, FileManagerError>>, FileManagerError> {
+        let mut unreadable = Vec::new();
+        let (mut updated_files, mut states) =
+            self.find_updated_files(pdf_files, &mut unreadable).await;
+        let mut deleted = self.hold_deletions(deleted);
+        let (renames, moved) = self
+            .match_renames(&mut updated_files, &mut states, &mut deleted)
+            .await;
+        let deleted = self.release_deletions(deleted).await;
+        let diffs = self.generate_diffs(&updated_files, &states, &moved).await;
+        for (path, last_path) in updated_files.iter() {
+            if let Some(Err(_)) = diffs.get(path.as_path()) {
+                continue;
+            }
+            if let Err(e) = self.record_revision(path, last_path, &states[path]).await {
+                tracing::warn!(path = %path.display(), "unable to store revision: {}", e);
+            }
+        }
+        let mut updated_pdfs = HashMap::new();
+        for (path, diff) in diffs {
+            match diff {
+                Ok(Some(diff_path)) => {
+                    updated_pdfs.insert(path, Ok(diff_path));
+                }
+                // New bytes that render the same need no diff; remember
+                // them so they are not compared again. The snapshot is
+                // refreshed as well, so it keeps matching the stored hash.
+                Ok(None) => match copy(path, &updated_files[path]).await {
+                    Ok(_) => {
+                        self.state().insert(path, states[path].clone());
+                        self.settler().succeeded(path);
+                    }
+                    Err(e) => {
+                        tracing::warn!(path = %path.display(), "unable to refresh snapshot: {}", e)
+                    }
+                },
+                Err(e) => {
+                    updated_pdfs.insert(path, Err(e));
+                }
+            }
+        }
+        // Diffs are already written; failing here would only make every
+        // later scan write them again.
+        let cache = self.fingerprint_cache().pending_write();
+        if let Err(e) = write_pending(cache).await {
+            self.fingerprint_cache().write_failed();
+            tracing::warn!("unable to save fingerprint cache: {}", e);
+        }
         let post_update_status = self.update_changed_pdfs(updated_pdfs, &updated_files).await;
-        Ok(post_update_status
+        // Failures are most likely files still being uploaded; only report
+        // them once the retries are used up.
+        let mut results: HashMap<_, _> = post_update_status
             .into_iter()
+            .filter(|(path, result)| {
+                let state = &states[*path];
+                match result {
+                    Ok(_) => self.state().insert(path, state.clone()),
+                    // Retrying would only crash the next worker; wait for
+                    // the file to change instead.
+                    Err(FileManagerError::Quarantined(_)) => {
+                        let state = FileState {
+                            quarantined: true,
+                            ..state.clone()
+                        };
+                        self.state().insert(path, state);
+                    }
+                    // Not the file's fault; tried again once pdfium is bound.
+                    Err(FileManagerError::PdfiumUnavailable) => {
+                        self.awaiting_pdfium().insert(path.to_path_buf());
+                        return false;
+                    }
+                    Err(_) => return self.settler().failed(path, state.snapshot()),
+                }
+                self.settler().succeeded(path);
+                true
+            })
             .map(|(p, r)| (p.to_path_buf(), r))
-            .collect())
+            .collect();
+        for (path, e) in unreadable {
+            results.insert(path, Err(e.into()));
+        }
+        for (current, last) in deleted {
+            let result = self.record_deletion(&current, &last).await;
+            if result.is_ok() {
+                self.state().remove(&current);
+                self.settler().forget(&current);
+            }
+            results.insert(current, result);
+        }
+        for rename in renames {
+            let result = self.record_rename(&rename).await;
+            if result.is_ok() {
+                self.state().remove(&rename.from);
+                self.state().insert(&rename.current, rename.state);
+                self.settler().forget(&rename.from);
+                self.settler().succeeded(&rename.current);
+            }
+            results.insert(rename.current, result);
+        }
+        self.save_indexes().await?;
+        Ok(results)
+    }
+
+    /// Write the version and state indexes back if they changed. Both are
+    /// serialized under their locks and written on a blocking thread. Only
+    /// failing to save the state index is an error.
+    async fn save_indexes(&self) -> io::Result<()> {
+        let versions = self.versions().pending_write();
+        if let Err(e) = write_pending(versions).await {
+            self.versions().write_failed();
+            tracing::warn!("unable to save version index: {}", e);
+        }
+        let state = self.state().pending_write();
+        if let Err(e) = write_pending(state).await {
+            self.state().write_failed();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Add `deleted` to the deletions held back so far, and return all of
+    /// them as candidates for renames.
+    fn hold_deletions(&self, deleted: Vec<(PathBuf, PathBuf)>) -> Vec<(PathBuf, PathBuf)> {
+        let mut held = self.held_deletions();
+        for (current, last) in deleted {
+            held.entry(current).or_insert((last, Instant::now()));
+        }
+        held.iter()
+            .map(|(current, (last, _))| (current.clone(), last.clone()))
+            .collect()
+    }
+
+    /// Which of the unmatched `deleted` files to record now. While a new
+    /// file is still settling the others stay held, up to
+    /// `MAX_DELETION_HOLD`: moves are often a copy followed by a delete,
+    /// and the copy can only be paired with the original once complete.
+    async fn release_deletions(&self, deleted: Vec<(PathBuf, PathBuf)>) -> Vec<(PathBuf, PathBuf)> {
+        let settling = self.settler().settling();
+        let new_file_settling = settling.iter().any(|path| self.state().get(path).is_none());
+        let mut held = std::mem::take(&mut *self.held_deletions());
+        let mut still_held = HashMap::new();
+        let mut released = Vec::new();
+        for (current, last) in deleted {
+            let since = held
+                .remove(&current)
+                .map_or_else(Instant::now, |(_, since)| since);
+            // Back again before it was recorded; not a deletion after all.
+            if tokio::fs::try_exists(&current).await.unwrap_or(false) {
+                continue;
+            }
+            if new_file_settling && since.elapsed() < MAX_DELETION_HOLD {
+                still_held.insert(current, (last, since));
+            } else {
+                released.push((current, last));
+            }
+        }
+        *self.held_deletions() = still_held;
+        released
+    }
+
+    /// Pair the new files in `updated_files` with files in `deleted`,
+    /// which are taken out, so moving a file does not produce a diff
+    /// against nothing. Files with unchanged content are returned as
+    /// renames and taken out of `updated_files` as well. Edited ones are
+    /// paired by the pages they share; their snapshot
+    /// and history move to the new path, so they are diffed as usual, and
+    /// the returned map names their previous path.
+    async fn match_renames(
+        &self,
+        updated_files: &mut HashMap<PathBuf, PathBuf>,
+        states: &mut HashMap<PathBuf, FileState>,
+        deleted: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> (Vec<Rename>, HashMap<PathBuf, PathBuf>) {
+        let mut renames = Vec::new();
+        let mut moved = HashMap::new();
+        if deleted.is_empty() {
+            return (renames, moved);
+        }
+        let mut new_files = Vec::new();
+        for (current, last) in updated_files.iter() {
+            if !tokio::fs::try_exists(last).await.unwrap_or(false) {
+                new_files.push(current.clone());
+            }
+        }
+        if new_files.is_empty() {
+            return (renames, moved);
+        }
+        let mut candidates = Vec::new();
+        for (current, snapshot) in std::mem::take(deleted) {
+            let known = self.state().get(&current);
+            let hash = match known {
+                Some(known) => Some(known.hash),
+                None => file_hash_async(&snapshot).await.ok(),
+            };
+            candidates.push(DeletedFile {
+                current,
+                snapshot,
+                hash,
+            });
+        }
+        new_files.retain(|current| {
+            let hash = &states[current].hash;
+            let Some(k) = candidates
+                .iter()
+                .position(|c| c.hash.as_ref() == Some(hash))
+            else {
+                return true;
+            };
+            let candidate = candidates.swap_remove(k);
+            updated_files.remove(current);
+            renames.push(Rename {
+                current: current.clone(),
+                state: states.remove(current).unwrap(),
+                from: candidate.current,
+                snapshot: candidate.snapshot,
+            });
+            false
+        });
+        for current in new_files {
+            if candidates.is_empty() {
+                break;
+            }
+            let Some(k) = self
+                .find_origin(&current, &states[&current].hash, &candidates)
+                .await
+            else {
+                continue;
+            };
+            let candidate = candidates.swap_remove(k);
+            if let Err(e) = self
+                .move_history(&candidate.current, &candidate.snapshot, &current)
+                .await
+            {
+                tracing::warn!(path = %current.display(), "unable to move history: {}", e);
+                candidates.push(candidate);
+                continue;
+            }
+            self.state().remove(&candidate.current);
+            self.settler().forget(&candidate.current);
+            let from = candidate
+                .current
+                .strip_prefix(&self.current_path)
+                .unwrap_or(&candidate.current)
+                .to_path_buf();
+            moved.insert(current, from);
+        }
+        deleted.extend(candidates.into_iter().map(|c| (c.current, c.snapshot)));
+        (renames, moved)
+    }
+
+    /// Which of `candidates` the new file `current` (with content `hash`)
+    /// most likely was before it was moved and edited: the one sharing the
+    /// most pages, if that is more than half of the larger document. A
+    /// matching file name only breaks ties.
+    async fn find_origin(
+        &self,
+        current: &Path,
+        hash: &str,
+        candidates: &[DeletedFile],
+    ) -> Option<usize> {
+        let new = self.page_fingerprints(current, hash).await?;
+        let mut best = None;
+        for (k, candidate) in candidates.iter().enumerate() {
+            let Some(hash) = &candidate.hash else {
+                continue;
+            };
+            let Some(old) = self.page_fingerprints(&candidate.snapshot, hash).await else {
+                continue;
+            };
+            let shared = shared_pages(&new, &old);
+            if shared * 2 <= new.len().max(old.len()) {
+                continue;
+            }
+            let score = (shared, candidate.current.file_name() == current.file_name());
+            if best.is_none_or(|(_, best)| score > best) {
+                best = Some((k, score));
+            }
+        }
+        best.map(|(k, _)| k)
+    }
+
+    /// Page fingerprints of the file at `path` with content `hash`,
+    /// computed if they are not cached yet. `None` if that fails, e.g.
+    /// because pdfium is unavailable.
+    async fn page_fingerprints(&self, path: &Path, hash: &str) -> Option<Vec<PageFingerprint>> {
+        let cached = self.fingerprint_cache().get(hash);
+        if cached.is_some() {
+            return cached;
+        }
+        if let Err(e) = self.run(Job::Fingerprint(path.to_path_buf())).await {
+            tracing::debug!(path = %path.display(), "unable to fingerprint: {}", e);
+            return None;
+        }
+        self.fingerprint_cache().get(hash)
+    }
+
+    /// Move the snapshot and version history of `from`, which was deleted
+    /// from `current_path`, to `to`.
+    async fn move_history(&self, from: &Path, snapshot: &Path, to: &Path) -> io::Result<()> {
+        let from = from.strip_prefix(&self.current_path).unwrap_or(from);
+        let to = to.strip_prefix(&self.current_path).unwrap_or(to);
+        let last = self.last_path.join(to);
+        create_parent(&last).await?;
+        move_file(snapshot, &last).await?;
+        self.versions().rename(from, to);
+        Ok(())
+    }
+
+    /// Move the snapshot and version history of a file renamed without
+    /// changes to its new path and record the rename. Only the sidecar is
+    /// written; the event shows the stored revision instead of a copy.
+    async fn record_rename(&self, rename: &Rename) -> Result<PathBuf, FileManagerError> {
+        self.move_history(&rename.from, &rename.snapshot, &rename.current)
+            .await?;
+        let relative = rename
+            .current
+            .strip_prefix(&self.current_path)
+            .unwrap_or(&rename.current);
+        let from = rename
+            .from
+            .strip_prefix(&self.current_path)
+            .unwrap_or(&rename.from);
+        let now = Utc::now();
+        // Documents without history (from before the version store) need
+        // the revision the event refers to.
+        self.record_version(
+            relative,
+            &self.last_path.join(relative),
+            &rename.state.hash,
+            now,
+        )
+        .await?;
+        let outpath = self.event_path(relative, "renamed", now);
+        create_parent(&outpath).await?;
+        let sidecar = DiffSidecar {
+            title: document_title(relative),
+            created: now,
+            source: relative.to_path_buf(),
+            location: self.current_path.clone(),
+            old_hash: Some(rename.state.hash.clone()),
+            new_hash: rename.state.hash.clone(),
+            stats: DiffStats::default(),
+            change: DocumentChange::Renamed,
+            renamed_from: Some(from.to_path_buf()),
+        };
+        sidecar.save(&outpath).await?;
+        Ok(outpath)
+    }
+
+    /// Where the content with `hash` is kept in the version store.
+    pub fn revision_path(&self, hash: &str) -> PathBuf {
+        self.versions().object_path(hash)
+    }
+
+    /// `<diff_path>/<relative dir>/<filename>.<kind>.<timestamp>.pdf`.
+    /// Mirroring the source's subdirectories keeps
+    /// equally named files in different folders apart.
+    fn event_path(&self, relative: &Path, kind: &str, now: DateTime<Utc>) -> PathBuf {
+        let filename = relative
+            .file_name()
+            .and_then(|v| v.to_str())
+            .unwrap_or("unknown_filename");
+        let outdir = match relative.parent() {
+            Some(parent) => self.diff_path.join(parent),
+            None => self.diff_path.clone(),
+        };
+        outdir.join(format!("{}.{}.{}.pdf", filename, kind, now.timestamp()))
+    }
+
+    /// Put the last known version of a deleted file into `diff_path` with a
+    /// sidecar marking the deletion, and archive or remove its snapshot.
+    /// The snapshot is gone by the time the event appears, so a failure on
+    /// either side is retried on the next scan without recording the
+    /// deletion twice.
+    async fn record_deletion(
+        &self,
+        current: &Path,
+        last: &Path,
+    ) -> Result<PathBuf, FileManagerError> {
+        let relative = current.strip_prefix(&self.current_path).unwrap_or(current);
+        let now = Utc::now();
+        let outpath = self.event_path(relative, "deleted", now);
+        create_parent(&outpath).await?;
+        let known = self.state().get(current);
+        let hash = match known {
+            Some(known) => known.hash,
+            None => file_hash_async(last).await?,
+        };
+        // Like diffs, only renamed to the final name once the sidecar is
+        // written, so events never list the copy as a legacy diff.
+        let partial = outpath.with_extension("partial");
+        copy(last, &partial).await?;
+        if let Err(e) = self.remove_snapshot(last, &outpath).await {
+            tokio::fs::remove_file(&partial).await.ok();
+            return Err(e.into());
+        }
+        let sidecar = DiffSidecar {
+            title: document_title(relative),
+            created: now,
+            source: relative.to_path_buf(),
+            location: self.current_path.clone(),
+            old_hash: Some(hash.clone()),
+            new_hash: hash,
+            stats: DiffStats::default(),
+            change: DocumentChange::Deleted,
+            renamed_from: None,
+        };
+        let saved = match sidecar.save(&outpath).await {
+            Ok(()) => tokio::fs::rename(&partial, &outpath).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            // Put the snapshot back so the deletion is found again.
+            if let Err(e) = move_file(&partial, last).await {
+                tracing::warn!(path = %last.display(), "unable to restore snapshot: {}", e);
+            }
+            return Err(e.into());
+        }
+        Ok(outpath)
+    }
+
+    /// Archive the snapshot `last` of a file deleted in the event at
+    /// `outpath`, or remove it without an `archive_path`.
+    async fn remove_snapshot(&self, last: &Path, outpath: &Path) -> io::Result<()> {
+        match &self.archive_path {
+            Some(archive_path) => {
+                let archived = archive_path.join(outpath.strip_prefix(&self.diff_path).unwrap());
+                create_parent(&archived).await?;
+                move_file(last, &archived).await
+            }
+            None => tokio::fs::remove_file(last).await,
+        }
+    }
+
+    /// Add the current content of `path` to the version store. The first
+    /// time a document is recorded its previous version, still in
+    /// `last_path`, is stored first.
+    async fn record_revision(
+        &self,
+        path: &Path,
+        last_path: &Path,
+        state: &FileState,
+    ) -> io::Result<()> {
+        let document = path.strip_prefix(&self.current_path).unwrap_or(path);
+        let first = self.versions().revisions(document).is_empty();
+        if first {
+            if let Ok(hash) = file_hash_async(last_path).await {
+                let observed = metadata(last_path)
+                    .await
+                    .and_then(|m| m.modified())
+                    .map(DateTime::<Utc>::from)
+                    .unwrap_or_else(|_| Utc::now());
+                self.record_version(document, last_path, &hash, observed)
+                    .await?;
+            }
+        }
+        self.record_version(document, path, &state.hash, Utc::now())
+            .await
+    }
+
+    /// Store the content of `source` (hashing to `hash`) as the newest
+    /// revision of `document`, unless it already is. The store is only
+    /// locked to look up and update the index, not during the copy.
+    async fn record_version(
+        &self,
+        document: &Path,
+        source: &Path,
+        hash: &str,
+        observed: DateTime<Utc>,
+    ) -> io::Result<()> {
+        let object = {
+            let versions = self.versions();
+            if versions.is_current(document, hash) {
+                return Ok(());
+            }
+            versions.object_path(hash)
+        };
+        let size = store_object(object, source.to_path_buf(), observed).await?;
+        self.versions().push(
+            document,
+            Revision {
+                hash: hash.to_string(),
+                observed,
+                size,
+            },
+        );
+        Ok(())
+    }
+
+    /// Diff between the revisions of `document` (relative to
+    /// `current_path`) that were current at `from` and at `to`. Diffs are
+    /// cached in the version store, keyed by the two content hashes and the
+    /// location's diff settings.
+    pub async fn diff_revisions(
+        &self,
+        document: &Path,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<PathBuf, FileManagerError> {
+        let (old, new) = {
+            let versions = self.versions();
+            let no_revision = || FileManagerError::NoRevision(document.to_path_buf());
+            let old = versions.at(document, from).ok_or_else(no_revision)?;
+            let new = versions.at(document, to).ok_or_else(no_revision)?;
+            (old.hash.clone(), new.hash.clone())
+        };
+        // Every page would be identical, leaving an empty diff.
+        if old == new {
+            return Err(FileManagerError::Unchanged(document.to_path_buf()));
+        }
+        let out_path = self.versions().diff_path(&old, &new, &self.settings.key());
+        if tokio::fs::try_exists(&out_path).await.unwrap_or(false) {
+            return Ok(out_path);
+        }
+        // Written under a name unique to this request so concurrent
+        // requests neither serve a half-written diff nor write the same
+        // file.
+        let partial = out_path.with_extension(format!("{:08x}.partial", rand::random::<u32>()));
+        let result = self
+            .run(Job::Diff(DiffJob {
+                new: self.revision_path(&new),
+                old: self.revision_path(&old),
+                out: partial.clone(),
+                force: true,
+                settings: self.settings.clone(),
+                fingerprints: self.worker_fingerprints(&[&new, &old]),
+            }))
+            .await;
+        if let Err(e) = result {
+            tokio::fs::remove_file(&partial).await.ok();
+            return Err(e);
+        }
+        tokio::fs::rename(&partial, &out_path).await?;
+        Ok(out_path)
     }
 
     async fn update_changed_pdfs<'a>(
@@ -116,106 +952,697 @@ impl FileManager {
         res
     }
 
-    fn generate_updated_pdfs<'a>(
+    /// Diff every file against its snapshot, writing the diff and its
+    /// sidecar. `None` for files whose pages all render the same. `moved`
+    /// holds the previous paths of files that were moved.
+    async fn generate_diffs<'a>(
         &self,
-        tasks: HashMap<&'a Path, Result<Vec<Comparison>, FileManagerError>>,
-    ) -> HashMap<&'a Path, Result<PathBuf, FileManagerError>> {
-        tasks
+        files: &'a HashMap<PathBuf, PathBuf>,
+        states: &HashMap<PathBuf, FileState>,
+        moved: &HashMap<PathBuf, PathBuf>,
+    ) -> HashMap<&'a Path, Result<Option<PathBuf>, FileManagerError>> {
+        let jobs = files.iter().map(|(path, last_path)| async move {
+            let renamed_from = moved.get(path).cloned();
+            let diff = self
+                .generate_diff(path, last_path, &states[path], renamed_from)
+                .await;
+            (path.as_path(), diff)
+        });
+        futures::future::join_all(jobs).await.into_iter().collect()
+    }
+
+    async fn generate_diff(
+        &self,
+        path: &Path,
+        last_path: &Path,
+        state: &FileState,
+        renamed_from: Option<PathBuf>,
+    ) -> Result<Option<PathBuf>, FileManagerError> {
+        let relative = path.strip_prefix(&self.current_path).unwrap_or(path);
+        let now = Utc::now();
+        let old_hash = file_hash_async(last_path).await.ok();
+        let outpath = self.event_path(relative, "diff", now);
+        let hashes: Vec<&str> = [Some(state.hash.as_str()), old_hash.as_deref()]
             .into_iter()
-            .map(|(path, comparisons)| {
-                let res = comparisons.and_then(|comparisons| {
-                    let filename = path
-                        .file_name()
-                        .and_then(|v| v.to_str())
-                        .unwrap_or("unknown_filename");
-                    let outpath = self.diff_path.join(format!(
-                        "{}.diff.{}.pdf",
-                        filename,
-                        Utc::now().timestamp()
-                    ));
-                    self.pdf_editor
-                        .mark_differences(path, &comparisons, &outpath)?;
-                    Ok(outpath)
-                });
-                (path, res)
-            })
+            .flatten()
+            .collect();
+        // Only renamed to the final name once the sidecar is written, so
+        // events never list a diff without it.
+        let partial = outpath.with_extension("partial");
+        let job = DiffJob {
+            new: path.to_path_buf(),
+            old: last_path.to_path_buf(),
+            out: partial.clone(),
+            force: false,
+            settings: self.settings.clone(),
+            fingerprints: self.worker_fingerprints(&hashes),
+        };
+        let stats = match self.run(Job::Diff(job)).await {
+            Ok(Some(stats)) => stats,
+            other => {
+                tokio::fs::remove_file(&partial).await.ok();
+                return other.map(|_| None);
+            }
+        };
+        let sidecar = DiffSidecar {
+            title: document_title(relative),
+            created: now,
+            source: relative.to_path_buf(),
+            location: self.current_path.clone(),
+            old_hash,
+            new_hash: state.hash.clone(),
+            stats,
+            change: DocumentChange::Modified,
+            renamed_from,
+        };
+        let saved = match sidecar.save(&outpath).await {
+            Ok(()) => tokio::fs::rename(&partial, &outpath).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            tokio::fs::remove_file(&partial).await.ok();
+            return Err(e.into());
+        }
+        Ok(Some(outpath))
+    }
+
+    /// Cached fingerprints of the contents with `hashes`, to send along with
+    /// a job for a worker process. Empty without isolation, where the job
+    /// uses the cache directly.
+    fn worker_fingerprints(&self, hashes: &[&str]) -> Vec<(String, Vec<PageFingerprint>)> {
+        if self.supervisor.is_none() {
+            return Vec::new();
+        }
+        let mut cache = self.fingerprint_cache();
+        hashes
+            .iter()
+            .filter_map(|hash| Some((hash.to_string(), cache.get(hash)?)))
             .collect()
     }
 
-    fn generate_comparisons<'a>(
+    /// Run `job` on the worker pool, in a worker process if isolation is
+    /// enabled.
+    async fn run(&self, job: Job) -> Result<Option<DiffStats>, FileManagerError> {
+        let pdfium = self
+            .pdfium
+            .get()
+            .ok_or(FileManagerError::PdfiumUnavailable)?;
+        match &self.supervisor {
+            Some(supervisor) => {
+                self.pool
+                    .limit(supervisor.run(&job, &self.fingerprint_cache))
+                    .await
+            }
+            None => {
+                let cache = self.fingerprint_cache.clone();
+                self.pool.run(move || run_job(pdfium, cache, &job)).await
+            }
+        }
+    }
+
+    /// Of `pdf_files`, the ones whose content differs from the last
+    /// processed version, together with their new state. Size and mtime
+    /// only serve as a pre-filter: unchanged metadata skips hashing, but a
+    /// change is confirmed by hash, so rewrites with identical bytes are
+    /// ignored and restored files with older mtimes are still picked up.
+    ///
+    /// Files that cannot be read are retried like failed diffs and added
+    /// to `unreadable` once their retries are used up.
+    async fn find_updated_files(
         &self,
-        files: &'a HashMap<PathBuf, PathBuf>,
-    ) -> HashMap<&'a Path, Result<Vec<Comparison>, FileManagerError>> {
-        files
-            .iter()
-            .filter_map(|(current_path, last_path)| {
-                match self.pdf_comparison.compare_pdfs(current_path, last_path) {
-                    Ok(res) => {
-                        res.iter().find(|v| matches!(v, Comparison::Different(_)))?;
-                        Some((current_path.as_path(), Ok(res)))
+        pdf_files: Vec<(PathBuf, PathBuf)>,
+        unreadable: &mut Vec<(PathBuf, io::Error)>,
+    ) -> (HashMap<PathBuf, PathBuf>, HashMap<PathBuf, FileState>) {
+        let mut updated = HashMap::new();
+        let mut states = HashMap::new();
+        for (current, last) in pdf_files {
+            let meta = match metadata(&current).await {
+                Ok(v) => v,
+                Err(e) => {
+                    let unknown = Snapshot {
+                        size: 0,
+                        modified: None,
+                    };
+                    self.read_failed(&current, unknown, e, unreadable);
+                    continue;
+                }
+            };
+            let (size, modified) = (meta.len(), meta.modified().ok());
+            let known = self.state().get(&current);
+            if known
+                .as_ref()
+                .is_some_and(|k| k.matches_metadata(size, modified))
+            {
+                continue;
+            }
+            if !self.settler().ready(&current, Snapshot { size, modified }) {
+                continue;
+            }
+            let hash = match file_hash_async(&current).await {
+                Ok(v) => v,
+                Err(e) => {
+                    self.read_failed(&current, Snapshot { size, modified }, e, unreadable);
+                    continue;
+                }
+            };
+            let state = FileState {
+                size,
+                modified,
+                hash,
+                quarantined: false,
+            };
+            // Files processed before the index existed are checked against
+            // their `last_path` copy instead.
+            let previous = match known {
+                Some(known) => Some(known.hash),
+                None => file_hash_async(&last).await.ok(),
+            };
+            if previous.as_ref() == Some(&state.hash) {
+                self.state().insert(&current, state);
+                continue;
+            }
+            states.insert(current.clone(), state);
+            updated.insert(current, last);
+        }
+        (updated, states)
+    }
+
+    /// Drop `path` if it vanished since it was listed, otherwise record the
+    /// failure and add it to `unreadable` once it should be reported.
+    fn read_failed(
+        &self,
+        path: &Path,
+        snapshot: Snapshot,
+        error: io::Error,
+        unreadable: &mut Vec<(PathBuf, io::Error)>,
+    ) {
+        if error.kind() == io::ErrorKind::NotFound {
+            self.settler().forget(path);
+        } else if self.settler().failed(path, snapshot) {
+            unreadable.push((path.to_path_buf(), error));
+        }
+    }
+
+    /// The PDFs among the paths reported by the watcher, descending into
+    /// directories that were created or moved in, and the files deleted
+    /// with the paths that no longer exist.
+    async fn watched_pdf_files(
+        &self,
+        paths: Vec<PathBuf>,
+    ) -> Result<(Vec<(PathBuf, PathBuf)>, Vec<(PathBuf, PathBuf)>), FileManagerError> {
+        let mut result = Vec::new();
+        // Keyed by the deleted file: removing a folder reports both the
+        // folder and every file in it.
+        let mut deleted = HashMap::new();
+        for path in paths {
+            let Ok(relative) = path.strip_prefix(&self.current_path) else {
+                continue;
+            };
+            let last = self.last_path.join(relative);
+            match metadata(&path).await {
+                Ok(meta) if meta.is_dir() => {
+                    if self.filter.allows_dir(relative) {
+                        result.append(&mut self.find_pdf_files(path, last).await?)
                     }
-                    Err(e) => Some((current_path.as_path(), Err(FileManagerError::Compare(e)))),
                 }
-            })
-            .collect()
+                Ok(meta)
+                    if path.extension() == Some(OsStr::new("pdf"))
+                        && self.filter.allows_file(relative, Some(meta.len())) =>
+                {
+                    result.push((path, last))
+                }
+                Ok(_) => {}
+                // Gone again.
+                Err(_) => {
+                    self.settler().forget(&path);
+                    deleted.extend(self.find_deleted_files(last).await?);
+                }
+            }
+        }
+        Ok((result, deleted.into_iter().collect()))
+    }
+
+    /// Snapshots at or below `last` (a file or directory in `last_path`)
+    /// whose file in `current_path` no longer exists, as pairs of the
+    /// missing file and its snapshot. The version store and archive are
+    /// skipped when they live inside `last_path`.
+    async fn find_deleted_files(
+        &self,
+        last: PathBuf,
+    ) -> Result<Vec<(PathBuf, PathBuf)>, FileManagerError> {
+        let skip = [
+            Some(self.versions().root().to_path_buf()),
+            self.archive_path.clone(),
+        ];
+        let mut deleted = Vec::new();
+        let mut pending = vec![last];
+        while let Some(path) = pending.pop() {
+            if skip.iter().flatten().any(|s| *s == path) {
+                continue;
+            }
+            let Ok(relative) = path.strip_prefix(&self.last_path) else {
+                continue;
+            };
+            let meta = match metadata(&path).await {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if meta.is_dir() {
+                if !relative.as_os_str().is_empty() && !self.filter.allows_dir(relative) {
+                    continue;
+                }
+                let mut entries = read_dir(&path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    pending.push(entry.path());
+                }
+                continue;
+            }
+            if path.extension() != Some(OsStr::new("pdf"))
+                || !self.filter.allows_file(relative, None)
+            {
+                continue;
+            }
+            let current = self.current_path.join(relative);
+            if !tokio::fs::try_exists(&current).await? {
+                deleted.push((current, path));
+            }
+        }
+        Ok(deleted)
     }
 
-    fn find_updated_files(
+    /// Every PDF below `current_path` that passes the location's filter,
+    /// paired with its counterpart below `last_path`, which may not exist
+    /// yet.
+    fn find_pdf_files(
+        &self,
         current_path: PathBuf,
         last_path: PathBuf,
-    ) -> futures::future::BoxFuture<'static, Result<Vec<(PathBuf, PathBuf)>, FileManagerError>>
-    {
+    ) -> futures::future::BoxFuture<'_, Result<Vec<(PathBuf, PathBuf)>, FileManagerError>> {
         Box::pin(async move {
             let mut entries = read_dir(&current_path).await?;
             let mut result = Vec::new();
             while let Some(entry) = entries.next_entry().await? {
                 let file_type: FileTypeEnum = entry.file_type().await?.into();
-                let file_name = entry.file_name();
-                let last_path_file_path = last_path.join(&file_name);
-                let last_path_metadata = metadata(&last_path_file_path)
-                    .await
-                    .map(|v| (FileTypeEnum::from(&v), v));
-                match (file_type, last_path_metadata) {
-                    (FileTypeEnum::File, Ok((FileTypeEnum::File, last_meta))) => {
-                        let current_meta = metadata(entry.path()).await?;
-                        if current_meta.modified()? > last_meta.modified()?
-                            && entry.path().extension() == Some(OsStr::new("pdf"))
-                        {
-                            result.push((entry.path(), last_path_file_path));
+                let last_path_file_path = last_path.join(entry.file_name());
+                let path = entry.path();
+                let relative = path.strip_prefix(&self.current_path).unwrap_or(&path);
+                match file_type {
+                    FileTypeEnum::File => {
+                        if path.extension() != Some(OsStr::new("pdf")) {
+                            continue;
                         }
-                    }
-                    (FileTypeEnum::File, Err(e)) => {
-                        if let io::ErrorKind::NotFound = e.kind() {
-                            result.push((entry.path(), last_path_file_path));
-                        } else {
-                            return Err(FileManagerError::Io(e));
+                        let size = match self.filter.limits_size() {
+                            true => Some(entry.metadata().await?.len()),
+                            false => None,
+                        };
+                        if self.filter.allows_file(relative, size) {
+                            result.push((path, last_path_file_path));
                         }
                     }
-                    (FileTypeEnum::File, Ok((FileTypeEnum::Dir, _last_meta))) => {
-                        result.push((entry.path(), last_path_file_path));
-                    }
-                    (FileTypeEnum::Dir, Err(e)) => {
-                        if let io::ErrorKind::NotFound = e.kind() {
-                            result.append(
-                                &mut FileManager::find_updated_files(
-                                    entry.path(),
-                                    last_path_file_path,
-                                )
-                                .await?,
-                            )
-                        } else {
-                            return Err(FileManagerError::Io(e));
+                    FileTypeEnum::Dir => {
+                        if self.filter.allows_dir(relative) {
+                            result
+                                .append(&mut self.find_pdf_files(path, last_path_file_path).await?);
                         }
                     }
-                    (FileTypeEnum::Dir, _) => {
-                        result.append(
-                            &mut FileManager::find_updated_files(entry.path(), last_path_file_path)
-                                .await?,
-                        );
-                    }
                 }
             }
             Ok(result)
         })
     }
 }
+
+async fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => create_dir_all(parent).await,
+        None => Ok(()),
+    }
+}
+
+/// Rename, falling back to copy and remove across filesystems.
+async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_err() {
+        copy(from, to).await?;
+        tokio::fs::remove_file(from).await?;
+    }
+    Ok(())
+}
+
+/// `/`-separated relative path without the extension, e.g. `Uni/Physics/Notes`
+/// for `Uni/Physics/Notes.pdf`.
+fn document_title(relative: &Path) -> String {
+    relative
+        .with_extension("")
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directory below the system temp dir, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path =
+                std::env::temp_dir().join(format!("documents-test-{:016x}", rand::random::<u64>()));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// A location below `root` that is not watched and uses `settle_time`
+    /// and `baseline`.
+    async fn manager(root: &Path, settle_time: u64, baseline: &str) -> FileManager {
+        let location: Location = serde_json::from_value(serde_json::json!({
+            "current_path": root.join("current"),
+            "last_path": root.join("last"),
+            "diff_path": root.join("diffs"),
+            "watch": false,
+            "settle_time": settle_time,
+            "baseline": baseline,
+        }))
+        .unwrap();
+        std::fs::create_dir_all(&location.current_path).unwrap();
+        let cache = FingerprintCache::load(root.join("fingerprints.json")).await;
+        let state = StateIndex::load(root.join("state.json")).await;
+        FileManager::new(
+            Arc::new(PdfiumLoader::new(None)),
+            &location,
+            Arc::new(Mutex::new(cache)),
+            Arc::new(Mutex::new(state)),
+            Arc::new(WorkerPool::new(1)),
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn write(path: &Path, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    fn state(content: &str) -> FileState {
+        FileState {
+            size: content.len() as u64,
+            modified: None,
+            hash: crate::cache::hex_sha256(content.as_bytes()),
+            quarantined: false,
+        }
+    }
+
+    /// Cache fingerprints for `content`, one page per id, so renames are
+    /// matched without pdfium.
+    fn seed_pages(fm: &FileManager, content: &str, ids: &[u64]) {
+        let pages = ids
+            .iter()
+            .map(|&id| PageFingerprint {
+                dimensions: (100, 141),
+                cells: Vec::new(),
+                phash: id,
+                hash: id,
+            })
+            .collect();
+        fm.fingerprint_cache()
+            .insert(crate::cache::hex_sha256(content.as_bytes()), pages);
+    }
+
+    /// Run `match_renames` for the new file `new` with `content` against
+    /// the deleted `old.pdf`.
+    async fn match_new_file(
+        fm: &FileManager,
+        new: &Path,
+        content: &str,
+    ) -> (
+        Vec<Rename>,
+        HashMap<PathBuf, PathBuf>,
+        Vec<(PathBuf, PathBuf)>,
+    ) {
+        write(new, content);
+        let relative = new.strip_prefix(&fm.current_path).unwrap();
+        let mut updated = HashMap::from([(new.to_path_buf(), fm.last_path.join(relative))]);
+        let mut states = HashMap::from([(new.to_path_buf(), state(content))]);
+        let mut deleted = vec![(
+            fm.current_path.join("old.pdf"),
+            fm.last_path.join("old.pdf"),
+        )];
+        let (renames, moved) = fm
+            .match_renames(&mut updated, &mut states, &mut deleted)
+            .await;
+        (renames, moved, deleted)
+    }
+
+    #[test]
+    fn titles_are_relative_paths_without_extension() {
+        assert_eq!(document_title(Path::new("Notes.pdf")), "Notes");
+        assert_eq!(
+            document_title(Path::new("Uni/Physics/Notes.pdf")),
+            "Uni/Physics/Notes"
+        );
+        assert_eq!(document_title(Path::new("Uni/v1.2.pdf")), "Uni/v1.2");
+    }
+
+    #[tokio::test]
+    async fn deleted_file_is_recorded_with_its_last_version() {
+        let dir = TempDir::new();
+        let fm = manager(&dir.0, 0, "never").await;
+        let current = fm.current_path.join("sub/notes.pdf");
+        let snapshot = fm.last_path.join("sub/notes.pdf");
+        write(&snapshot, "last version");
+
+        let results = fm.update().await.unwrap();
+        let event = results[&current].as_ref().unwrap();
+        assert_eq!(read(event), "last version");
+        let sidecar = DiffSidecar::load(event).await.unwrap();
+        assert_eq!(sidecar.change, DocumentChange::Deleted);
+        assert_eq!(sidecar.title, "sub/notes");
+        assert!(!snapshot.exists());
+        assert!(!event.with_extension("partial").exists());
+
+        assert!(fm.update().await.unwrap().is_empty(), "recorded once");
+    }
+
+    #[tokio::test]
+    async fn deletion_is_not_recorded_until_its_snapshot_is_archived() {
+        let dir = TempDir::new();
+        let mut fm = manager(&dir.0, 0, "never").await;
+        let current = fm.current_path.join("notes.pdf");
+        let snapshot = fm.last_path.join("notes.pdf");
+        write(&snapshot, "last version");
+        // A file where the archive directory should be.
+        write(&dir.0.join("archive"), "");
+        fm.archive_path = Some(dir.0.join("archive"));
+
+        let results = fm.update().await.unwrap();
+        assert!(results[&current].is_err());
+        assert!(snapshot.exists());
+        assert_eq!(std::fs::read_dir(&fm.diff_path).unwrap().count(), 0);
+
+        fm.archive_path = None;
+        let results = fm.update().await.unwrap();
+        assert!(results[&current].is_ok());
+        assert!(!snapshot.exists());
+        // Only the copy and its sidecar.
+        assert_eq!(std::fs::read_dir(&fm.diff_path).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_renamed() {
+        let dir = TempDir::new();
+        let fm = manager(&dir.0, 0, "never").await;
+        write(&fm.last_path.join("old.pdf"), "same");
+
+        let new = fm.current_path.join("new.pdf");
+        let (renames, moved, deleted) = match_new_file(&fm, &new, "same").await;
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].from, fm.current_path.join("old.pdf"));
+        assert!(moved.is_empty());
+        assert!(deleted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn edited_file_sharing_most_pages_is_moved() {
+        let dir = TempDir::new();
+        let fm = manager(&dir.0, 0, "never").await;
+        let snapshot = fm.last_path.join("old.pdf");
+        write(&snapshot, "old");
+        seed_pages(&fm, "old", &[1, 2, 3, 4, 5]);
+        seed_pages(&fm, "new", &[1, 2, 3, 4, 9]);
+        fm.versions().push(
+            Path::new("old.pdf"),
+            Revision {
+                hash: crate::cache::hex_sha256(b"old"),
+                observed: Utc::now(),
+                size: 3,
+            },
+        );
+
+        let new = fm.current_path.join("sub/new.pdf");
+        let (renames, moved, deleted) = match_new_file(&fm, &new, "new").await;
+        assert!(renames.is_empty());
+        assert!(deleted.is_empty());
+        assert_eq!(moved[&new], PathBuf::from("old.pdf"));
+        assert!(!snapshot.exists());
+        assert_eq!(read(&fm.last_path.join("sub/new.pdf")), "old");
+        assert_eq!(fm.versions().revisions(Path::new("sub/new.pdf")).len(), 1);
+        assert!(fm.versions().revisions(Path::new("old.pdf")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn file_sharing_half_the_pages_is_not_moved() {
+        let dir = TempDir::new();
+        let fm = manager(&dir.0, 0, "never").await;
+        let snapshot = fm.last_path.join("old.pdf");
+        write(&snapshot, "old");
+        seed_pages(&fm, "old", &[1, 2, 3, 4]);
+        seed_pages(&fm, "new", &[1, 2, 8, 9]);
+
+        let new = fm.current_path.join("new.pdf");
+        let (renames, moved, deleted) = match_new_file(&fm, &new, "new").await;
+        assert!(renames.is_empty());
+        assert!(moved.is_empty());
+        assert_eq!(
+            deleted,
+            vec![(fm.current_path.join("old.pdf"), snapshot.clone())]
+        );
+        assert_eq!(read(&snapshot), "old");
+    }
+
+    #[tokio::test]
+    async fn deletion_is_held_while_a_new_file_settles() {
+        let dir = TempDir::new();
+        let fm = manager(&dir.0, 3600, "never").await;
+        let original = fm.current_path.join("original.pdf");
+        let snapshot = fm.last_path.join("original.pdf");
+        write(&snapshot, "original");
+        let copy = fm.current_path.join("copy.pdf");
+        write(&copy, "partial copy");
+
+        let results = fm
+            .process(
+                vec![(copy.clone(), fm.last_path.join("copy.pdf"))],
+                vec![(original.clone(), snapshot.clone())],
+            )
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+        assert!(fm.held_deletions().contains_key(&original));
+        assert!(snapshot.exists());
+
+        // The copy is abandoned, so nothing is left to pair it with.
+        std::fs::remove_file(&copy).unwrap();
+        fm.settler().forget(&copy);
+        let results = fm.process(Vec::new(), Vec::new()).await.unwrap();
+        assert!(results[&original].is_ok());
+        assert!(fm.held_deletions().is_empty());
+        assert!(!snapshot.exists());
+    }
+
+    #[tokio::test]
+    async fn auto_baseline_is_taken_for_a_new_location_only() {
+        let dir = TempDir::new();
+        let fm = manager(&dir.0, 0, "auto").await;
+        let current = fm.current_path.join("a.pdf");
+        write(&current, "v1");
+
+        assert!(fm.update().await.unwrap().is_empty());
+        assert_eq!(read(&fm.last_path.join("a.pdf")), "v1");
+        assert_eq!(fm.versions().revisions(Path::new("a.pdf")).len(), 1);
+        assert!(fm.state().get(&current).is_some());
+        assert!(!fm.take_baseline().await.unwrap());
+
+        // Snapshots exist now, so a restart does not take another one.
+        let fm = manager(&dir.0, 0, "auto").await;
+        assert!(!fm.take_baseline().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn always_baseline_is_taken_on_every_start() {
+        let dir = TempDir::new();
+        let fm = manager(&dir.0, 0, "always").await;
+        let snapshot = fm.last_path.join("a.pdf");
+        write(&snapshot, "v1");
+        write(&fm.current_path.join("a.pdf"), "v2");
+
+        assert!(fm.update().await.unwrap().is_empty());
+        assert_eq!(read(&snapshot), "v2");
+        assert!(!dir.0.join("diffs").exists());
+        assert!(!fm.take_baseline().await.unwrap(), "once per start");
+
+        let fm = manager(&dir.0, 0, "always").await;
+        assert!(fm.take_baseline().await.unwrap());
+    }
+
+    /// A manager whose watcher watches an unrelated, empty directory, so
+    /// only the paths queued by the test are reported.
+    async fn watched_manager(root: &Path) -> FileManager {
+        let mut fm = manager(root, 0, "auto").await;
+        let unwatched = root.join("unwatched");
+        std::fs::create_dir_all(&unwatched).unwrap();
+        fm.watcher = Some(LocationWatcher::new(&unwatched).unwrap());
+        fm
+    }
+
+    fn reported(results: &HashMap<PathBuf, Result<PathBuf, FileManagerError>>) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = results.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn poll_processes_the_reported_paths_until_events_are_lost() {
+        let dir = TempDir::new();
+        let fm = watched_manager(&dir.0).await;
+        let (a, b) = (fm.current_path.join("a.pdf"), fm.current_path.join("b.pdf"));
+        write(&a, "a");
+        write(&b, "b");
+        // The first poll always scans everything.
+        assert!(fm.poll().await.unwrap().is_empty());
+        assert!(fm.last_path.join("b.pdf").exists());
+
+        std::fs::remove_file(&a).unwrap();
+        std::fs::remove_file(&b).unwrap();
+        assert!(fm.poll().await.unwrap().is_empty(), "nothing reported");
+
+        fm.watcher.as_ref().unwrap().queue(a.clone());
+        let results = fm.poll().await.unwrap();
+        assert_eq!(reported(&results), vec![a]);
+        assert!(fm.last_path.join("b.pdf").exists());
+
+        fm.watcher.as_ref().unwrap().overflow();
+        let results = fm.poll().await.unwrap();
+        assert_eq!(reported(&results), vec![b]);
+    }
+
+    #[tokio::test]
+    async fn poll_rescans_once_the_interval_passed() {
+        let dir = TempDir::new();
+        let mut fm = watched_manager(&dir.0).await;
+        let a = fm.current_path.join("a.pdf");
+        write(&a, "a");
+        assert!(fm.poll().await.unwrap().is_empty());
+
+        std::fs::remove_file(&a).unwrap();
+        assert!(fm.poll().await.unwrap().is_empty());
+
+        fm.rescan_interval = Duration::ZERO;
+        let results = fm.poll().await.unwrap();
+        assert_eq!(reported(&results), vec![a]);
+    }
+}
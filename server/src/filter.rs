@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+/// Which files of a location are tracked, read from
+/// `[config.locations.filter]`. Globs are matched against paths relative to
+/// `current_path`; `*` stays within one directory, `**` spans several.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FilterOptions {
+    /// If given, only files matching one of these are tracked.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Files, and directories with everything in them, that are never
+    /// tracked, e.g. `**/Trash` or `**/*.backup.pdf`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Also track files and directories whose name starts with a dot.
+    #[serde(default)]
+    pub hidden: bool,
+    /// How many directory levels below `current_path` are descended into;
+    /// 0 tracks only the top-level files. Unlimited by default.
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Files larger than this many bytes are skipped.
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+}
+
+/// Compiled `FilterOptions`.
+#[derive(Debug)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    hidden: bool,
+    max_depth: Option<usize>,
+    max_file_size: Option<u64>,
+}
+
+impl PathFilter {
+    pub fn new(options: &FilterOptions) -> Result<Self, globset::Error> {
+        let include = match options.include.is_empty() {
+            true => None,
+            false => Some(glob_set(&options.include)?),
+        };
+        Ok(PathFilter {
+            include,
+            exclude: glob_set(&options.exclude)?,
+            hidden: options.hidden,
+            max_depth: options.max_depth,
+            max_file_size: options.max_file_size,
+        })
+    }
+
+    /// Whether to descend into the directory at `relative`.
+    pub fn allows_dir(&self, relative: &Path) -> bool {
+        if self
+            .max_depth
+            .is_some_and(|max| relative.components().count() > max)
+        {
+            return false;
+        }
+        relative
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .all(|p| self.allows_entry(p))
+    }
+
+    /// Whether the file at `relative` is tracked, including the size limit
+    /// if `size` is known.
+    pub fn allows_file(&self, relative: &Path, size: Option<u64>) -> bool {
+        if let Some(parent) = relative.parent() {
+            if !parent.as_os_str().is_empty() && !self.allows_dir(parent) {
+                return false;
+            }
+        }
+        if !self.allows_entry(relative) {
+            return false;
+        }
+        if self
+            .include
+            .as_ref()
+            .is_some_and(|set| !set.is_match(relative))
+        {
+            return false;
+        }
+        match (self.max_file_size, size) {
+            (Some(max), Some(size)) => size <= max,
+            _ => true,
+        }
+    }
+
+    pub fn limits_size(&self) -> bool {
+        self.max_file_size.is_some()
+    }
+
+    fn allows_entry(&self, relative: &Path) -> bool {
+        let hidden = relative
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        (self.hidden || !hidden) && !self.exclude.is_match(relative)
+    }
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(options: FilterOptions) -> PathFilter {
+        PathFilter::new(&options).unwrap()
+    }
+
+    #[test]
+    fn everything_but_hidden_entries_by_default() {
+        let filter = compile(FilterOptions::default());
+        assert!(filter.allows_file(Path::new("a.pdf"), Some(1)));
+        assert!(filter.allows_file(Path::new("Uni/Physics/a.pdf"), None));
+        assert!(filter.allows_dir(Path::new("Uni/Physics")));
+        assert!(!filter.allows_file(Path::new(".a.pdf"), None));
+        assert!(!filter.allows_dir(Path::new("Uni/.trash")));
+        assert!(!filter.allows_file(Path::new(".trash/a.pdf"), None));
+        assert!(!filter.limits_size());
+    }
+
+    #[test]
+    fn hidden_entries_when_enabled() {
+        let filter = compile(FilterOptions {
+            hidden: true,
+            ..FilterOptions::default()
+        });
+        assert!(filter.allows_file(Path::new(".trash/.a.pdf"), None));
+    }
+
+    #[test]
+    fn max_depth() {
+        let filter = compile(FilterOptions {
+            max_depth: Some(1),
+            ..FilterOptions::default()
+        });
+        assert!(filter.allows_file(Path::new("a.pdf"), None));
+        assert!(filter.allows_dir(Path::new("Uni")));
+        assert!(filter.allows_file(Path::new("Uni/a.pdf"), None));
+        assert!(!filter.allows_dir(Path::new("Uni/Physics")));
+        assert!(!filter.allows_file(Path::new("Uni/Physics/a.pdf"), None));
+
+        let filter = compile(FilterOptions {
+            max_depth: Some(0),
+            ..FilterOptions::default()
+        });
+        assert!(filter.allows_file(Path::new("a.pdf"), None));
+        assert!(!filter.allows_dir(Path::new("Uni")));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let filter = compile(FilterOptions {
+            include: vec!["Uni/**".into()],
+            exclude: vec!["**/Trash".into(), "**/*.backup.pdf".into()],
+            ..FilterOptions::default()
+        });
+        assert!(filter.allows_file(Path::new("Uni/Physics/a.pdf"), None));
+        assert!(!filter.allows_file(Path::new("Work/a.pdf"), None));
+        assert!(!filter.allows_dir(Path::new("Uni/Trash")));
+        assert!(!filter.allows_file(Path::new("Uni/Trash/a.pdf"), None));
+        assert!(!filter.allows_file(Path::new("Uni/a.backup.pdf"), None));
+    }
+
+    #[test]
+    fn max_file_size_applies_when_known() {
+        let filter = compile(FilterOptions {
+            max_file_size: Some(10),
+            ..FilterOptions::default()
+        });
+        assert!(filter.limits_size());
+        assert!(filter.allows_file(Path::new("a.pdf"), Some(10)));
+        assert!(!filter.allows_file(Path::new("a.pdf"), Some(11)));
+        assert!(filter.allows_file(Path::new("a.pdf"), None));
+    }
+}
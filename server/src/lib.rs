@@ -1,17 +1,19 @@
 //! Documents plugin: tracks PDF changes between two parallel directory
-//! trees (current vs last), generates a diff PDF marking which page rows
-//! changed, then exposes the diff as a signed-URL download. Events are
-//! derived from filenames in the diff directory (the timestamp is encoded
-//! in the filename).
+//! trees (current vs last), generates a diff PDF with the changed regions
+//! marked as annotations, then exposes the diff as a signed-URL download.
+//! Events are built from the JSON sidecar written next to each diff; diffs
+//! without one fall back to the filename (the timestamp is encoded in the
+//! filename).
 
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use base64::Engine;
 use chrono::DateTime;
 use rocket::fs::{FileServer, NamedFile, Options};
 use rocket::http::Status;
+use rocket::serde::json::Json;
 use rocket::{get, routes, Build, Rocket, Route, State};
 use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
 use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
@@ -23,20 +25,96 @@ use tokio::fs::read_dir;
 
 use timeline_plugin_sdk::auth::AuthedClient;
 use timeline_plugin_sdk::{
-    APIError, APIResult, CompressedEvent, Context, Manifest, Plugin, Style, TimeRange, Timing,
+    APIResult, CompressedEvent, Context, Manifest, Plugin, Style, TimeRange, Timing,
 };
 
+mod align;
+mod cache;
 mod files;
+mod filter;
+mod loader;
 mod pdf;
+mod persist;
+mod pool;
+mod settle;
+mod sidecar;
+mod state;
+mod stats;
+mod text;
+mod versions;
+mod watch;
+mod worker;
 
-use crate::files::FileManager;
-use crate::pdf::get_pdfium;
+pub use crate::worker::run_worker;
+
+use crate::cache::FingerprintCache;
+use crate::files::{BaselineMode, FileManager, FileManagerError};
+use crate::filter::FilterOptions;
+use crate::loader::{PdfiumLoader, PdfiumStatus};
+use crate::pdf::{ComparisonOptions, ContextOptions, DiffLayout};
+use crate::pool::{PoolStatus, WorkerPool};
+use crate::sidecar::{DiffSidecar, DocumentChange};
+use crate::state::StateIndex;
+use crate::stats::DiffStats;
+use crate::worker::{Supervisor, WorkerOptions};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Location {
     pub current_path: PathBuf,
     pub last_path: PathBuf,
     pub diff_path: PathBuf,
+    /// `marked` (default), `side_by_side` or `overlay`.
+    #[serde(default)]
+    pub diff_layout: DiffLayout,
+    #[serde(default)]
+    pub comparison: ComparisonOptions,
+    /// Unchanged pages kept around the changes (`marked` and `overlay`).
+    #[serde(default)]
+    pub context: ContextOptions,
+    /// Which files below `current_path` are tracked.
+    #[serde(default)]
+    pub filter: FilterOptions,
+    /// Watch `current_path` for changes instead of only rescanning it.
+    /// Disable for mounts without inotify support, e.g. network shares.
+    #[serde(default = "default_watch")]
+    pub watch: bool,
+    /// Seconds between full rescans. Defaults to 600 while watching and 60
+    /// otherwise.
+    #[serde(default)]
+    pub rescan_interval: Option<u64>,
+    /// Seconds a file's size and mtime must stay unchanged before it is
+    /// processed, so uploads in progress are not picked up.
+    #[serde(default = "default_settle_time")]
+    pub settle_time: u64,
+    /// Retries, with exponential backoff, of a file that fails to process
+    /// before the error is reported.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// When to snapshot the location without generating diffs: `auto`
+    /// (default, only while `last_path` is empty), `always` (on every
+    /// start) or `never`.
+    #[serde(default)]
+    pub baseline: BaselineMode,
+    /// Where snapshots of deleted files are moved. Without it they are
+    /// removed; the deletion event keeps a copy either way.
+    #[serde(default)]
+    pub archive_path: Option<PathBuf>,
+    /// Where every observed revision of the location's documents is kept,
+    /// stored once per content hash. Defaults to `<last_path>/.versions`.
+    #[serde(default)]
+    pub versions_path: Option<PathBuf>,
+}
+
+fn default_watch() -> bool {
+    true
+}
+
+fn default_settle_time() -> u64 {
+    10
+}
+
+fn default_max_retries() -> u32 {
+    5
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -55,22 +133,72 @@ pub struct DocumentsConfig {
     /// first run. Defaults to `<plugin_root>/signing_key.pem`.
     #[serde(default)]
     pub signing_key_path: Option<PathBuf>,
+    /// Where per-page fingerprints are cached between runs. Defaults to
+    /// `<plugin_root>/fingerprints.json`.
+    #[serde(default)]
+    pub fingerprint_cache_path: Option<PathBuf>,
+    /// Where the size, mtime and hash of every processed file are kept.
+    /// Defaults to `<plugin_root>/state.json`.
+    #[serde(default)]
+    pub state_index_path: Option<PathBuf>,
+    /// How many PDF comparisons and diffs run at once, across all
+    /// locations. Defaults to the number of CPUs.
+    #[serde(default)]
+    pub pdf_workers: Option<usize>,
+    /// Crash isolation of pdfium, see `[config.worker]`.
+    #[serde(default)]
+    pub worker: WorkerOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignedDocument {
     pub path: String,
     pub signature: String,
+    /// Page statistics of the diff; missing for diffs written before they
+    /// were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<DiffStats>,
+    /// Signed ID of the source document, for diffing arbitrary revisions
+    /// through `/revisions`. Missing for diffs without sidecar.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document: Option<SignedDocumentId>,
+    /// For `deleted`, `path` is the last known version instead of a diff,
+    /// for `renamed` the unchanged document.
+    #[serde(default)]
+    pub change: DocumentChange,
+    /// Previous name of a renamed or moved document.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_from: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedDocumentId {
+    /// Absolute path of the source document.
+    pub id: String,
+    pub signature: String,
 }
 
 pub struct DocumentsPlugin {
     ctx: Context,
     config: DocumentsConfig,
     file_managers: Arc<Vec<FileManager>>,
+    pool: Arc<WorkerPool>,
+    state: Arc<Mutex<StateIndex>>,
+    pdfium: Arc<PdfiumLoader>,
     signing_key: SigningKey<Sha256>,
     verifying_key: VerifyingKey<Sha256>,
 }
 
+/// Served at `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginStatus {
+    pub pdfium: PdfiumStatus,
+    pub pool: PoolStatus,
+    /// Files skipped until they change because they crashed or hung a pdf
+    /// worker.
+    pub quarantined: Vec<PathBuf>,
+}
+
 impl Plugin for DocumentsPlugin {
     async fn new(ctx: Context) -> anyhow::Result<Self> {
         let config: DocumentsConfig = ctx
@@ -87,24 +215,62 @@ impl Plugin for DocumentsPlugin {
         let signing_key: SigningKey<Sha256> = SigningKey::new(private_key);
         let verifying_key = signing_key.verifying_key();
 
-        let pdfium = Arc::new(get_pdfium(config.pdfium_path.as_deref()));
-        let file_managers: Vec<FileManager> = config
-            .locations
-            .iter()
-            .map(|v| {
-                FileManager::new(
-                    pdfium.clone(),
-                    v.current_path.clone(),
-                    v.last_path.clone(),
-                    v.diff_path.clone(),
-                )
-            })
-            .collect();
+        let cache_path = config
+            .fingerprint_cache_path
+            .clone()
+            .unwrap_or_else(|| ctx.config.plugin_root().join("fingerprints.json"));
+        let fingerprint_cache =
+            Arc::new(Mutex::new(FingerprintCache::load(cache_path.clone()).await));
+
+        let state_path = config
+            .state_index_path
+            .clone()
+            .unwrap_or_else(|| ctx.config.plugin_root().join("state.json"));
+        let state = Arc::new(Mutex::new(StateIndex::load(state_path).await));
+
+        let workers = config.pdf_workers.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|v| v.get())
+                .unwrap_or(1)
+        });
+        let pool = Arc::new(WorkerPool::new(workers));
+        let supervisor = config.worker.isolate.then(|| {
+            Arc::new(Supervisor::new(
+                &config.worker,
+                config.pdfium_path.clone(),
+                cache_path,
+                workers,
+            ))
+        });
+
+        // Without pdfium the plugin still serves existing diffs; processing
+        // starts once binding succeeds on a later retry.
+        let pdfium = Arc::new(PdfiumLoader::new(config.pdfium_path.clone()));
+        if let Some(error) = pdfium.status().error {
+            ctx.errors.report(error);
+        }
+        let mut file_managers = Vec::new();
+        for location in &config.locations {
+            let file_manager = FileManager::new(
+                pdfium.clone(),
+                location,
+                fingerprint_cache.clone(),
+                state.clone(),
+                pool.clone(),
+                supervisor.clone(),
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("location: {}", e))?;
+            file_managers.push(file_manager);
+        }
 
         Ok(Self {
             ctx,
             config,
             file_managers: Arc::new(file_managers),
+            pool,
+            state,
+            pdfium,
             signing_key,
             verifying_key,
         })
@@ -128,8 +294,8 @@ impl Plugin for DocumentsPlugin {
     async fn events(&self, range: TimeRange) -> APIResult<Vec<CompressedEvent>> {
         let mut out = Vec::new();
         for fm in self.file_managers.iter() {
-            let mut entries = match read_dir(&fm.diff_path).await {
-                Ok(e) => e,
+            let diffs = match list_diffs(&fm.diff_path, |t| range.includes(t)).await {
+                Ok(v) => v,
                 Err(e) => {
                     self.ctx
                         .errors
@@ -137,26 +303,40 @@ impl Plugin for DocumentsPlugin {
                     continue;
                 }
             };
-            while let Some(entry) = entries
-                .next_entry()
-                .await
-                .map_err(|e| APIError::Custom(format!("dir entry: {}", e)))?
-            {
-                let path = entry.path();
-                let Some((title, time)) = parse_diff_filename(&path) else {
-                    continue;
+            for diff in diffs {
+                let sidecar = diff.sidecar;
+                let document = sidecar.as_ref().map(|sidecar| {
+                    let id = sidecar.location.join(&sidecar.source);
+                    let id = id.to_string_lossy().into_owned();
+                    SignedDocumentId {
+                        signature: sign_string(&self.signing_key, &revision_payload(&id)),
+                        id,
+                    }
+                });
+                let change = sidecar.as_ref().map(|v| v.change).unwrap_or_default();
+                let renamed_from = sidecar
+                    .as_ref()
+                    .and_then(|v| v.renamed_from.as_deref())
+                    .map(document_name);
+                let path = match &sidecar {
+                    Some(sidecar) if !diff.has_pdf => fm.revision_path(&sidecar.new_hash),
+                    _ => diff.path,
                 };
-                if !range.includes(&time) {
-                    continue;
-                }
+                let stats = sidecar
+                    .filter(|v| v.change == DocumentChange::Modified)
+                    .map(|v| v.stats);
                 let path_str = path.to_string_lossy().into_owned();
                 let signature = sign_string(&self.signing_key, &path_str);
                 out.push(CompressedEvent {
-                    title,
-                    time: Timing::Instant(time),
+                    title: diff.title,
+                    time: Timing::Instant(diff.time),
                     data: serde_json::to_value(SignedDocument {
                         path: path_str,
                         signature,
+                        stats,
+                        document,
+                        change,
+                        renamed_from,
                     })?,
                 });
             }
@@ -165,8 +345,12 @@ impl Plugin for DocumentsPlugin {
     }
 
     async fn request_loop(&self) -> Option<Duration> {
+        // Retries binding even while no file needs it, at most once a
+        // minute (see `PdfiumLoader`), so `/status` and the files waiting
+        // for pdfium catch up within a minute of it appearing.
+        self.pdfium.get();
         for fm in self.file_managers.iter() {
-            match fm.update().await {
+            match fm.poll().await {
                 Ok(map) => {
                     for (path, result) in map {
                         if let Err(e) = result {
@@ -177,21 +361,30 @@ impl Plugin for DocumentsPlugin {
                     }
                 }
                 Err(e) => {
-                    self.ctx
-                        .errors
-                        .report(format!("filemanager init: {}", e));
+                    self.ctx.errors.report(format!("filemanager init: {}", e));
                 }
             }
         }
-        Some(Duration::from_secs(60))
+        self.file_managers
+            .iter()
+            .map(FileManager::poll_interval)
+            .min()
+            .or(Some(Duration::from_secs(60)))
     }
 
     fn routes(&self) -> Vec<Route> {
-        routes![get_file]
+        routes![get_file, get_revision_diff, get_status]
     }
 
     fn rocket_attach(&self, rocket: Rocket<Build>) -> Rocket<Build> {
-        let mut rocket = rocket.manage(VerifyingKeyState(self.verifying_key.clone()));
+        let mut rocket = rocket
+            .manage(VerifyingKeyState(self.verifying_key.clone()))
+            .manage(FileManagersState(self.file_managers.clone()))
+            .manage(StatusState {
+                pdfium: self.pdfium.clone(),
+                pool: self.pool.clone(),
+                state: self.state.clone(),
+            });
         if let Some(pdfjs) = &self.config.pdfjs_path {
             rocket = rocket.mount(
                 "/js",
@@ -219,13 +412,185 @@ async fn get_file(
     NamedFile::open(file).await.map_err(|_| Status::NotFound)
 }
 
+struct FileManagersState(Arc<Vec<FileManager>>);
+
+/// Diff between the revisions of `document` that were current at the unix
+/// timestamps `from` and `to` (which must be later), generated on first
+/// request.
+#[get("/revisions/<document>/<from>/<to>/<signature>")]
+async fn get_revision_diff(
+    _auth: AuthedClient,
+    document: &str,
+    from: i64,
+    to: i64,
+    signature: &str,
+    verifying_key: &State<VerifyingKeyState>,
+    file_managers: &State<FileManagersState>,
+) -> Result<NamedFile, Status> {
+    if !verify_string(
+        &verifying_key.inner().0,
+        &revision_payload(document),
+        signature,
+    ) {
+        return Err(Status::Unauthorized);
+    }
+    if from >= to {
+        return Err(Status::BadRequest);
+    }
+    let from = DateTime::from_timestamp(from, 0).ok_or(Status::BadRequest)?;
+    let to = DateTime::from_timestamp(to, 0).ok_or(Status::BadRequest)?;
+    let document = Path::new(document);
+    let (fm, relative) = file_managers
+        .inner()
+        .0
+        .iter()
+        .find_map(|fm| Some((fm, document.strip_prefix(&fm.current_path).ok()?)))
+        .ok_or(Status::NotFound)?;
+    match fm.diff_revisions(relative, from, to).await {
+        Ok(path) => NamedFile::open(path).await.map_err(|_| Status::NotFound),
+        Err(FileManagerError::NoRevision(_) | FileManagerError::Unchanged(_)) => {
+            Err(Status::NotFound)
+        }
+        Err(FileManagerError::PdfiumUnavailable) => Err(Status::ServiceUnavailable),
+        Err(e) => {
+            tracing::warn!("revision diff: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+struct StatusState {
+    pdfium: Arc<PdfiumLoader>,
+    pool: Arc<WorkerPool>,
+    state: Arc<Mutex<StateIndex>>,
+}
+
+#[get("/status")]
+fn get_status(_auth: AuthedClient, status: &State<StatusState>) -> Json<PluginStatus> {
+    let quarantined = status
+        .state
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .quarantined();
+    Json(PluginStatus {
+        pdfium: status.pdfium.status(),
+        pool: status.pool.status(),
+        quarantined,
+    })
+}
+
 // ---- helpers ----
 
+/// Every `.pdf` below `root`, including the subdirectories that mirror the
+/// source layout.
+async fn find_diffs(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut diffs = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                dirs.push(path);
+            } else if path.extension().and_then(|v| v.to_str()) == Some("pdf") {
+                diffs.push(path);
+            } else if path.extension().and_then(|v| v.to_str()) == Some("json") {
+                // Sidecars without a PDF of their own, i.e. renames.
+                let pdf = path.with_extension("pdf");
+                if !tokio::fs::try_exists(&pdf).await? {
+                    diffs.push(pdf);
+                }
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+/// A diff listed by `events`.
+struct ListedDiff {
+    /// Missing for renames without changes, which show the stored revision
+    /// instead.
+    path: PathBuf,
+    has_pdf: bool,
+    title: String,
+    time: DateTime<chrono::Utc>,
+    /// `None` for diffs written before sidecars existed.
+    sidecar: Option<DiffSidecar>,
+}
+
+/// The diffs below `diff_root` created at a time `includes` accepts.
+async fn list_diffs(
+    diff_root: &Path,
+    includes: impl Fn(&DateTime<chrono::Utc>) -> bool,
+) -> std::io::Result<Vec<ListedDiff>> {
+    let mut diffs = Vec::new();
+    for path in find_diffs(diff_root).await? {
+        // The name carries the creation time in whole seconds, so only the
+        // sidecars of diffs within a second of the range are read.
+        let Some((name_title, name_time)) = parse_diff_filename(&path) else {
+            continue;
+        };
+        if !includes(&name_time) && !includes(&(name_time + chrono::Duration::seconds(1))) {
+            continue;
+        }
+        let sidecar = DiffSidecar::load(&path).await;
+        let (title, time) = match &sidecar {
+            Some(sidecar) => {
+                let title = match (sidecar.change, &sidecar.renamed_from) {
+                    (DocumentChange::Deleted, _) => format!("{} (deleted)", sidecar.title),
+                    (_, Some(from)) => {
+                        format!("{} (renamed from {})", sidecar.title, document_name(from))
+                    }
+                    _ => sidecar.title.clone(),
+                };
+                (title, sidecar.created)
+            }
+            None => (legacy_title(diff_root, &path, name_title), name_time),
+        };
+        if !includes(&time) {
+            continue;
+        }
+        // Renames without changes point at the stored revision; any other
+        // sidecar without its PDF is a diff still being saved.
+        let has_pdf = tokio::fs::try_exists(&path).await.unwrap_or(false);
+        let renamed = sidecar
+            .as_ref()
+            .is_some_and(|v| v.change == DocumentChange::Renamed);
+        if !has_pdf && !renamed {
+            continue;
+        }
+        diffs.push(ListedDiff {
+            path,
+            has_pdf,
+            title,
+            time,
+            sidecar,
+        });
+    }
+    Ok(diffs)
+}
+
+/// Title for a diff without sidecar: the filename title, prefixed with the
+/// diff's subdirectory below `diff_root`, if any.
+fn legacy_title(diff_root: &Path, diff: &Path, title: String) -> String {
+    match diff.parent().and_then(|p| p.strip_prefix(diff_root).ok()) {
+        Some(dir) if dir.as_os_str().is_empty() => title,
+        Some(dir) => format!("{}/{}", document_name(dir), title),
+        None => title,
+    }
+}
+
+/// `/`-separated form of a path relative to a location.
+fn document_name(relative: &Path) -> String {
+    let parts: Vec<_> = relative.iter().map(|c| c.to_string_lossy()).collect();
+    parts.join("/")
+}
+
 fn parse_diff_filename(path: &Path) -> Option<(String, DateTime<chrono::Utc>)> {
-    // `<title>.diff.<unix_seconds>.pdf`
+    // `<title>.<kind>.<unix_seconds>.pdf`
     let name = path.file_name()?.to_str()?;
     let parts: Vec<&str> = name.split('.').collect();
-    if parts.len() < 4 {
+    if parts.len() < 4 || parts[parts.len() - 1] != "pdf" {
         return None;
     }
     let ts: i64 = parts[parts.len() - 2].parse().ok()?;
@@ -240,6 +605,12 @@ fn sign_string(signing_key: &SigningKey<Sha256>, string: &str) -> String {
     base64::prelude::BASE64_STANDARD.encode(signature.to_vec())
 }
 
+/// What a document ID is signed as. The prefix keeps the signature from
+/// also passing `/file`, which would serve the source document itself.
+fn revision_payload(id: &str) -> String {
+    format!("revision:{}", id)
+}
+
 fn verify_string(verifying_key: &VerifyingKey<Sha256>, string: &str, signature: &str) -> bool {
     let Ok(bytes) = base64::prelude::BASE64_STANDARD.decode(signature) else {
         return false;
@@ -274,3 +645,109 @@ async fn load_or_generate_key(path: &Path) -> anyhow::Result<RsaPrivateKey> {
     tracing::info!(path = %path.display(), "generated new documents signing key");
     Ok(key)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREATED: i64 = 1_700_000_000;
+
+    fn at(seconds: i64) -> DateTime<chrono::Utc> {
+        DateTime::from_timestamp(seconds, 0).unwrap()
+    }
+
+    fn sidecar(title: &str, change: DocumentChange, renamed_from: Option<&str>) -> DiffSidecar {
+        DiffSidecar {
+            title: title.to_string(),
+            created: at(CREATED),
+            source: PathBuf::from(format!("{}.pdf", title)),
+            location: PathBuf::from("/documents"),
+            old_hash: Some("old".to_string()),
+            new_hash: "new".to_string(),
+            stats: DiffStats::default(),
+            change,
+            renamed_from: renamed_from.map(PathBuf::from),
+        }
+    }
+
+    fn write(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+
+    #[tokio::test]
+    async fn diffs_are_listed_from_sidecars_and_legacy_names() {
+        let root = std::env::temp_dir().join(format!("diffs-test-{:016x}", rand::random::<u64>()));
+        let modified = root.join(format!("Uni/Physics/Notes.pdf.diff.{}.pdf", CREATED));
+        write(&modified);
+        sidecar("Uni/Physics/Notes", DocumentChange::Modified, None)
+            .save(&modified)
+            .await
+            .unwrap();
+        let legacy = root.join(format!("Uni/Essay.diff.{}.pdf", CREATED));
+        write(&legacy);
+        // Only the sidecar of a renamed file without changes is written.
+        let renamed = root.join(format!("Moved.pdf.renamed.{}.pdf", CREATED));
+        sidecar("Moved", DocumentChange::Renamed, Some("Old/Moved.pdf"))
+            .save(&renamed)
+            .await
+            .unwrap();
+        // A deletion still being saved.
+        let deleted = root.join(format!("Gone.pdf.deleted.{}.pdf", CREATED));
+        write(&deleted.with_extension("partial"));
+        sidecar("Gone", DocumentChange::Deleted, None)
+            .save(&deleted)
+            .await
+            .unwrap();
+
+        let mut diffs = list_diffs(&root, |_| true).await.unwrap();
+        diffs.sort_by(|a, b| a.title.cmp(&b.title));
+        let listed: Vec<_> = diffs
+            .iter()
+            .map(|d| {
+                let change = d.sidecar.as_ref().map(|v| v.change);
+                (d.title.as_str(), change, d.path.clone(), d.has_pdf)
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (
+                    "Moved (renamed from Old/Moved.pdf)",
+                    Some(DocumentChange::Renamed),
+                    renamed,
+                    false
+                ),
+                ("Uni/Essay", None, legacy, true),
+                (
+                    "Uni/Physics/Notes",
+                    Some(DocumentChange::Modified),
+                    modified,
+                    true
+                ),
+            ]
+        );
+        assert!(diffs.iter().all(|d| d.time == at(CREATED)));
+
+        // Diffs named outside the range are skipped without their sidecar.
+        let later = list_diffs(&root, |t| *t > at(CREATED + 1)).await.unwrap();
+        assert!(later.is_empty());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn legacy_titles_carry_the_diff_subdirectory() {
+        let root = Path::new("/diffs");
+        let title = |diff: &str| legacy_title(root, &root.join(diff), "Notes".to_string());
+        assert_eq!(title("Notes.diff.1.pdf"), "Notes");
+        assert_eq!(title("Uni/Physics/Notes.diff.1.pdf"), "Uni/Physics/Notes");
+        assert_eq!(
+            legacy_title(
+                root,
+                Path::new("/elsewhere/Notes.diff.1.pdf"),
+                "Notes".to_string()
+            ),
+            "Notes"
+        );
+    }
+}
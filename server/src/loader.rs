@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use pdfium_render::prelude::Pdfium;
+use serde::Serialize;
+
+use crate::pdf::get_pdfium;
+
+/// How long to wait before trying to bind pdfium again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Binds the library at the configured path, if any.
+type Bind<P> = Box<dyn Fn(Option<&Path>) -> Result<P, String> + Send + Sync>;
+
+/// Binds pdfium on demand. A missing library does not stop the plugin: it
+/// keeps serving existing diffs and recording deletions and renames, and
+/// retries binding every `RETRY_INTERVAL` until diffing can resume.
+pub struct PdfiumLoader<P = Pdfium> {
+    library_path: Option<PathBuf>,
+    bind: Bind<P>,
+    state: Mutex<LoaderState<P>>,
+}
+
+struct LoaderState<P> {
+    pdfium: Option<Arc<P>>,
+    error: Option<String>,
+    last_attempt: Option<Instant>,
+    unavailable_since: Option<DateTime<Utc>>,
+}
+
+impl<P> Default for LoaderState<P> {
+    fn default() -> Self {
+        LoaderState {
+            pdfium: None,
+            error: None,
+            last_attempt: None,
+            unavailable_since: None,
+        }
+    }
+}
+
+/// Served at `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PdfiumStatus {
+    pub available: bool,
+    /// Why the last attempt to bind failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unavailable_since: Option<DateTime<Utc>>,
+}
+
+impl PdfiumLoader {
+    /// Try to bind right away; see `get`.
+    pub fn new(library_path: Option<PathBuf>) -> Self {
+        PdfiumLoader::with_bind(
+            library_path,
+            Box::new(|path| get_pdfium(path).map_err(|e| e.to_string())),
+        )
+    }
+}
+
+impl<P> PdfiumLoader<P> {
+    fn with_bind(library_path: Option<PathBuf>, bind: Bind<P>) -> Self {
+        let loader = PdfiumLoader {
+            library_path,
+            bind,
+            state: Mutex::new(LoaderState::default()),
+        };
+        loader.get();
+        loader
+    }
+
+    /// The bound library, or `None` while it is unavailable. Binding is
+    /// retried when the last attempt is older than `RETRY_INTERVAL`.
+    pub fn get(&self) -> Option<Arc<P>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pdfium) = &state.pdfium {
+            return Some(pdfium.clone());
+        }
+        if state
+            .last_attempt
+            .is_some_and(|t| t.elapsed() < RETRY_INTERVAL)
+        {
+            return None;
+        }
+        state.last_attempt = Some(Instant::now());
+        match (self.bind)(self.library_path.as_deref()) {
+            Ok(pdfium) => {
+                if state.unavailable_since.is_some() {
+                    tracing::info!("pdfium loaded, resuming processing");
+                }
+                let pdfium = Arc::new(pdfium);
+                *state = LoaderState {
+                    pdfium: Some(pdfium.clone()),
+                    ..LoaderState::default()
+                };
+                Some(pdfium)
+            }
+            Err(e) => {
+                let error = format!(
+                    "unable to load pdfium ({}); set [config].pdfium_path or place libpdfium.so in CWD",
+                    e
+                );
+                if state.error.as_ref() != Some(&error) {
+                    tracing::warn!("{}", error);
+                }
+                state.error = Some(error);
+                state.unavailable_since.get_or_insert_with(Utc::now);
+                None
+            }
+        }
+    }
+
+    pub fn status(&self) -> PdfiumStatus {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        PdfiumStatus {
+            available: state.pdfium.is_some(),
+            error: state.error.clone(),
+            unavailable_since: state.unavailable_since,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// A loader whose library is there once `available` is set. Every
+    /// attempt to bind is counted in `attempts`.
+    fn loader(available: Arc<AtomicBool>, attempts: Arc<AtomicUsize>) -> PdfiumLoader<()> {
+        PdfiumLoader::with_bind(
+            None,
+            Box::new(move |_| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                if available.load(Ordering::Relaxed) {
+                    Ok(())
+                } else {
+                    Err("missing".to_string())
+                }
+            }),
+        )
+    }
+
+    /// Pretend the last attempt was `RETRY_INTERVAL` ago.
+    fn age_last_attempt(loader: &PdfiumLoader<()>) {
+        let mut state = loader.state.lock().unwrap();
+        state.last_attempt = state
+            .last_attempt
+            .and_then(|t| t.checked_sub(RETRY_INTERVAL));
+    }
+
+    #[test]
+    fn binding_is_retried_at_most_once_per_interval() {
+        let available = Arc::new(AtomicBool::new(false));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let loader = loader(available.clone(), attempts.clone());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        let status = loader.status();
+        assert!(!status.available);
+        assert!(status.error.unwrap().contains("missing"));
+        assert!(status.unavailable_since.is_some());
+
+        // The library shows up, but the last attempt is too recent.
+        available.store(true, Ordering::Relaxed);
+        assert!(loader.get().is_none());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+
+        age_last_attempt(&loader);
+        assert!(loader.get().is_some());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        let status = loader.status();
+        assert!(status.available);
+        assert!(status.error.is_none() && status.unavailable_since.is_none());
+
+        // Bound for good.
+        available.store(false, Ordering::Relaxed);
+        age_last_attempt(&loader);
+        assert!(loader.get().is_some());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn repeated_failures_keep_the_first_unavailable_time() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let loader = loader(Arc::new(AtomicBool::new(false)), attempts.clone());
+        let since = loader.status().unavailable_since;
+
+        age_last_attempt(&loader);
+        assert!(loader.get().is_none());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(loader.status().unavailable_since, since);
+    }
+}
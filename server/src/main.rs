@@ -2,13 +2,21 @@ use timeline_plugin_documents_server::DocumentsPlugin;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let _ = tracing::subscriber::set_global_default(
-        tracing_subscriber::FmtSubscriber::builder()
-            .with_env_filter(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| "info".into()),
-            )
-            .finish(),
+    let args: Vec<String> = std::env::args().collect();
+    let subscriber = tracing_subscriber::FmtSubscriber::builder().with_env_filter(
+        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
     );
+    // pdf worker started by the plugin. Its stdout carries the replies, so
+    // it logs to stderr.
+    if args.get(1).map(String::as_str) == Some("worker") {
+        let _ = tracing::subscriber::set_global_default(
+            subscriber.with_writer(std::io::stderr).finish(),
+        );
+        let config = args
+            .get(2)
+            .ok_or_else(|| anyhow::anyhow!("worker: missing config"))?;
+        return timeline_plugin_documents_server::run_worker(config).await;
+    }
+    let _ = tracing::subscriber::set_global_default(subscriber.finish());
     timeline_plugin_sdk::launch::<DocumentsPlugin>("config.toml").await
 }
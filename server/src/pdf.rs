@@ -1,11 +1,18 @@
 use {
-    image::{RgbImage, Rgba, RgbaImage},
+    crate::{
+        align::{align, PageFingerprint, PageMatch},
+        cache::{file_hash, FingerprintCache},
+        text::{diff_words, extract_words, TextDiff},
+    },
+    chrono::{DateTime, Local, Utc},
+    image::{Rgb, RgbImage},
     pdfium_render::prelude::*,
     rayon::prelude::*,
+    serde::{Deserialize, Serialize},
     std::{
         error::Error,
         path::Path,
-        sync::{atomic::AtomicUsize, Arc},
+        sync::{atomic::AtomicUsize, Arc, Mutex, MutexGuard},
     },
 };
 
@@ -13,81 +20,333 @@ use {
 pub enum Comparison {
     Identical,
     Different(DifferenceSegments),
+    /// Page without a counterpart in the old document.
+    Inserted(DifferenceSegments),
+    /// Page whose content was found at another position (`from`) in the
+    /// old document, with any changes relative to that page.
+    Moved {
+        from: u16,
+        changes: Option<DifferenceSegments>,
+    },
+    /// Page of the old document with no counterpart in the new one. These
+    /// follow the entries for the new document's pages.
+    Deleted(u16),
 }
 
-/// Build per-row difference segments between two equally-sized page renders.
-fn row_diff(img_a: &RgbImage, img_b: &RgbImage) -> DifferenceSegments {
-    let num_rows = img_a.rows().len();
-    if num_rows <= 1 {
-        return DifferenceSegments {
-            segments: vec![(0., 1.)],
-        };
+impl Comparison {
+    pub fn is_identical(&self) -> bool {
+        matches!(self, Comparison::Identical)
     }
-    let mut difference_builder = DifferenceSegementsBuilder::build();
-    img_a
-        .rows()
-        .zip(img_b.rows())
-        .enumerate()
-        .for_each(|(index, (r_a, r_b))| {
-            let mut equal = true;
-            for (p_a, p_b) in r_a.zip(r_b) {
-                if p_a != p_b {
-                    equal = false;
-                    break;
-                }
-            }
-            difference_builder.step(index as f64 / (num_rows - 1) as f64, !equal);
-        });
-    difference_builder.finish()
 }
 
-struct DifferenceSegementsBuilder {
-    segments: DifferenceSegments,
-    current_segment: Option<(f64, f64)>,
+/// Per-location comparison settings, read from `[config.locations.comparison]`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ComparisonOptions {
+    /// Also diff the page text extracted by pdfium, word by word.
+    #[serde(default)]
+    pub text_diff: bool,
+    /// Largest per-channel RGB difference (0-255) still treated as equal.
+    /// Absorbs anti-aliasing drift between pdfium versions and exporters.
+    #[serde(default)]
+    pub channel_tolerance: u8,
+    /// Pages with at most this many changed pixels count as identical.
+    #[serde(default)]
+    pub min_changed_pixels: usize,
+    /// Changed regions smaller than this many render pixels in both
+    /// directions are dropped as noise.
+    #[serde(default)]
+    pub min_region_size: u32,
+    /// Gaussian blur sigma applied to both renders before comparing.
+    #[serde(default)]
+    pub blur: f32,
+    /// Radius in render pixels by which changed pixels are grown before
+    /// being grouped into regions.
+    #[serde(default)]
+    pub dilate: u32,
 }
 
-impl DifferenceSegementsBuilder {
-    pub fn build() -> Self {
-        DifferenceSegementsBuilder {
-            segments: DifferenceSegments {
-                segments: Vec::new(),
-            },
-            current_segment: None,
+/// Axis-aligned rectangle in fractions of the page size, origin top-left.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub left: f64,
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+}
+
+impl Rect {
+    /// Convert a pdfium rect (points, origin bottom-left) into page fractions.
+    pub fn from_pdf_rect(rect: &PdfRect, page_width: f64, page_height: f64) -> Self {
+        Rect {
+            left: rect.left().value as f64 / page_width,
+            top: 1. - rect.top().value as f64 / page_height,
+            right: rect.right().value as f64 / page_width,
+            bottom: 1. - rect.bottom().value as f64 / page_height,
         }
     }
 
-    pub fn step(&mut self, position: f64, hit: bool) {
-        match &self.current_segment {
-            Some(v) => {
-                if hit {
-                    self.current_segment = Some((v.0, position));
-                } else {
-                    self.segments.segments.push(*v);
-                    self.current_segment = None;
-                }
+    /// Convert back into a pdfium rect (points, origin bottom-left).
+    pub fn to_pdf_rect(self, page_width: PdfPoints, page_height: PdfPoints) -> PdfRect {
+        let (width, height) = (page_width.value, page_height.value);
+        PdfRect::new_from_values(
+            (1. - self.bottom as f32) * height,
+            self.left as f32 * width,
+            (1. - self.top as f32) * height,
+            self.right as f32 * width,
+        )
+    }
+
+    pub const FULL: Rect = Rect {
+        left: 0.,
+        top: 0.,
+        right: 1.,
+        bottom: 1.,
+    };
+
+    /// Map a rect measured on a render rotated 90° clockwise (see
+    /// `rotate_if_landscape`) back onto the unrotated page.
+    pub fn unrotate_90(&self) -> Rect {
+        Rect {
+            left: self.top,
+            top: 1. - self.right,
+            right: self.bottom,
+            bottom: 1. - self.left,
+        }
+    }
+
+    pub fn union(&self, other: &Rect) -> Rect {
+        Rect {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    /// Whether the two rects overlap vertically by at least half the height
+    /// of the smaller one, i.e. sit on the same line of text.
+    pub fn same_line(&self, other: &Rect) -> bool {
+        let overlap = self.bottom.min(other.bottom) - self.top.max(other.top);
+        let height = (self.bottom - self.top).min(other.bottom - other.top);
+        overlap > 0. && overlap >= height / 2.
+    }
+}
+
+/// Side length in pixels of the tiles differing pixels are grouped into.
+const REGION_TILE_SIZE: u32 = 8;
+
+/// Per-pixel change mask between two equally-sized page renders.
+struct DiffMask {
+    width: u32,
+    height: u32,
+    changed: Vec<bool>,
+}
+
+impl DiffMask {
+    /// Mark every pixel where some channel differs by more than
+    /// `channel_tolerance`.
+    fn new(img_a: &RgbImage, img_b: &RgbImage, channel_tolerance: u8) -> Self {
+        let (width, height) = img_a.dimensions();
+        let changed = img_a
+            .pixels()
+            .zip(img_b.pixels())
+            .map(|(p_a, p_b)| pixel_differs(p_a, p_b, channel_tolerance))
+            .collect();
+        DiffMask {
+            width,
+            height,
+            changed,
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> bool {
+        self.changed[(y * self.width + x) as usize]
+    }
+
+    /// Grow every changed pixel into a square of the given radius so that
+    /// nearby strokes end up in the same region.
+    fn dilate(&self, radius: u32) -> DiffMask {
+        if radius == 0 {
+            return DiffMask {
+                width: self.width,
+                height: self.height,
+                changed: self.changed.clone(),
+            };
+        }
+        let (w, h) = (self.width as usize, self.height as usize);
+        let r = radius as usize;
+        let mut horizontal = vec![false; self.changed.len()];
+        for y in 0..h {
+            for x in 0..w {
+                let row = y * w;
+                horizontal[row + x] =
+                    (x.saturating_sub(r)..=(x + r).min(w - 1)).any(|nx| self.changed[row + nx]);
             }
-            None => {
-                if hit {
-                    self.current_segment = Some((position, position))
-                }
+        }
+        let mut changed = vec![false; self.changed.len()];
+        for y in 0..h {
+            for x in 0..w {
+                changed[y * w + x] =
+                    (y.saturating_sub(r)..=(y + r).min(h - 1)).any(|ny| horizontal[ny * w + x]);
             }
         }
+        DiffMask {
+            width: self.width,
+            height: self.height,
+            changed,
+        }
     }
+}
 
-    pub fn finish(mut self) -> DifferenceSegments {
-        match self.current_segment {
-            Some(v) => {
-                self.segments.segments.push(v);
-                self.segments
+fn pixel_differs(p_a: &Rgb<u8>, p_b: &Rgb<u8>, channel_tolerance: u8) -> bool {
+    p_a.0
+        .iter()
+        .zip(p_b.0.iter())
+        .any(|(c_a, c_b)| c_a.abs_diff(*c_b) > channel_tolerance)
+}
+
+/// Luma below which a render pixel counts as ink rather than paper.
+const INK_THRESHOLD: u8 = 235;
+
+/// Onion-skin composite of two equally-sized renders: ink only in `img_a`
+/// (new) is tinted green, ink only in `img_b` (old) red, and ink on both
+/// greyed out. Where both have ink that differs beyond `channel_tolerance`
+/// the new stroke wins.
+fn overlay_image(img_a: &RgbImage, img_b: &RgbImage, channel_tolerance: u8) -> RgbImage {
+    let luma =
+        |p: &Rgb<u8>| ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000) as u8;
+    let (width, height) = img_a.dimensions();
+    let mut overlay = RgbImage::new(width, height);
+    for ((out, p_a), p_b) in overlay.pixels_mut().zip(img_a.pixels()).zip(img_b.pixels()) {
+        let (l_a, l_b) = (luma(p_a), luma(p_b));
+        // Strength of the stroke, 0 for paper up to 255 for black ink.
+        let (s_a, s_b) = (255 - l_a, 255 - l_b);
+        *out = match (l_a < INK_THRESHOLD, l_b < INK_THRESHOLD) {
+            (false, false) => Rgb([255, 255, 255]),
+            (true, true) if !pixel_differs(p_a, p_b, channel_tolerance) => Rgb([128 + l_a / 2; 3]),
+            (true, _) => Rgb([255 - s_a, 255 - s_a / 3, 255 - s_a]),
+            (false, true) => Rgb([255 - s_b / 4, 255 - s_b, 255 - s_b]),
+        };
+    }
+    overlay
+}
+
+/// Rectangular change regions between two equally-sized page renders.
+/// Returns `None` when every region is below `min_region_size`, i.e. the
+/// pages only differ by noise.
+fn page_diff(
+    img_a: &RgbImage,
+    img_b: &RgbImage,
+    options: &ComparisonOptions,
+) -> Option<DifferenceSegments> {
+    let mask = DiffMask::new(img_a, img_b, options.channel_tolerance);
+    let regions: Vec<Rect> = region_diff(&mask, &mask.dilate(options.dilate))
+        .into_iter()
+        .filter(|((w, h), _)| *w.max(h) >= options.min_region_size)
+        .map(|(_, rect)| rect)
+        .collect();
+    if regions.is_empty() {
+        return None;
+    }
+    Some(DifferenceSegments {
+        regions,
+        ..DifferenceSegments::default()
+    })
+}
+
+/// Group the changed pixels of `grouping` into tiles and return the bounding
+/// box of every 8-connected component of changed tiles, measured on the
+/// pixels of `mask`, together with its size in pixels.
+fn region_diff(mask: &DiffMask, grouping: &DiffMask) -> Vec<((u32, u32), Rect)> {
+    let (width, height) = (mask.width, mask.height);
+    if width == 0 || height == 0 {
+        return vec![((width, height), Rect::FULL)];
+    }
+    let tiles_x = width.div_ceil(REGION_TILE_SIZE) as usize;
+    let tiles_y = height.div_ceil(REGION_TILE_SIZE) as usize;
+    let mut hit = vec![false; tiles_x * tiles_y];
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .filter(|(x, y)| grouping.get(*x, *y))
+        .for_each(|(x, y)| {
+            hit[(y / REGION_TILE_SIZE) as usize * tiles_x + (x / REGION_TILE_SIZE) as usize] = true;
+        });
+
+    let mut regions = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..hit.len() {
+        if !hit[start] {
+            continue;
+        }
+        hit[start] = false;
+        stack.push(start);
+        let (mut min_x, mut min_y) = (usize::MAX, usize::MAX);
+        let (mut max_x, mut max_y) = (0, 0);
+        while let Some(tile) = stack.pop() {
+            let (tx, ty) = (tile % tiles_x, tile / tiles_x);
+            min_x = min_x.min(tx);
+            min_y = min_y.min(ty);
+            max_x = max_x.max(tx);
+            max_y = max_y.max(ty);
+            for ny in ty.saturating_sub(1)..=(ty + 1).min(tiles_y - 1) {
+                for nx in tx.saturating_sub(1)..=(tx + 1).min(tiles_x - 1) {
+                    let neighbour = ny * tiles_x + nx;
+                    if hit[neighbour] {
+                        hit[neighbour] = false;
+                        stack.push(neighbour);
+                    }
+                }
             }
-            None => self.segments,
         }
+        // Tighten the tile bounds to the changed pixels of the undilated mask.
+        let tile = REGION_TILE_SIZE;
+        let xs = min_x as u32 * tile..((max_x as u32 + 1) * tile).min(width);
+        let ys = min_y as u32 * tile..((max_y as u32 + 1) * tile).min(height);
+        let Some((left, top, right, bottom)) = ys
+            .flat_map(|y| xs.clone().map(move |x| (x, y)))
+            .filter(|(x, y)| mask.get(*x, *y))
+            .fold(None, |acc: Option<(u32, u32, u32, u32)>, (x, y)| {
+                Some(match acc {
+                    Some((l, t, r, b)) => (l.min(x), t.min(y), r.max(x + 1), b.max(y + 1)),
+                    None => (x, y, x + 1, y + 1),
+                })
+            })
+        else {
+            continue;
+        };
+        regions.push((
+            (right - left, bottom - top),
+            Rect {
+                left: left as f64 / width as f64,
+                top: top as f64 / height as f64,
+                right: right as f64 / width as f64,
+                bottom: bottom as f64 / height as f64,
+            },
+        ));
     }
+    regions
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct DifferenceSegments {
-    pub segments: Vec<(f64, f64)>,
+    /// Bounding boxes of connected changed areas, in page fractions.
+    pub regions: Vec<Rect>,
+    /// Word-level text changes, present when `text_diff` is enabled.
+    pub text: Option<TextDiff>,
+    /// Page of the old document the changes are relative to, if any.
+    pub old_page: Option<u16>,
+    /// Onion-skin composite of the new page over the old one as PNG,
+    /// present for the `overlay` diff layout.
+    pub overlay: Option<Vec<u8>>,
+}
+
+impl DifferenceSegments {
+    fn full_page() -> Self {
+        DifferenceSegments {
+            regions: vec![Rect::FULL],
+            ..DifferenceSegments::default()
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -96,22 +355,13 @@ enum Similiarity {
     Similar(usize),
 }
 
-impl Similiarity {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match (self, other) {
-            (Similiarity::Different, Similiarity::Different) => std::cmp::Ordering::Equal,
-            (Similiarity::Different, Similiarity::Similar(_)) => std::cmp::Ordering::Greater,
-            (Similiarity::Similar(_), Similiarity::Different) => std::cmp::Ordering::Less,
-            (Similiarity::Similar(s), Similiarity::Similar(o)) => s.cmp(o),
-        }
-    }
-}
-
 #[derive(Debug)]
 pub enum PDFComparisonError {
     UnableToLoadPDF(PdfiumError),
     UnableToRenderPDF(PdfiumError),
     PdfiumError(PdfiumError),
+    UnableToEncodeImage(image::ImageError),
+    Io(std::io::Error),
 }
 
 impl Error for PDFComparisonError {}
@@ -122,6 +372,8 @@ impl std::fmt::Display for PDFComparisonError {
             Self::UnableToLoadPDF(e) => write!(f, "Was unable to load pdf: {}", e),
             Self::UnableToRenderPDF(e) => write!(f, "Was unable to render a pdf. Error: {}", e),
             Self::PdfiumError(e) => write!(f, "Unkown or unexpected pdfium error: {}", e),
+            Self::UnableToEncodeImage(e) => write!(f, "Was unable to encode an image: {}", e),
+            Self::Io(e) => write!(f, "Was unable to read or write a file: {}", e),
         }
     }
 }
@@ -132,27 +384,39 @@ impl From<PdfiumError> for PDFComparisonError {
     }
 }
 
-pub fn get_pdfium(library_path: Option<&std::path::Path>) -> Pdfium {
+impl From<std::io::Error> for PDFComparisonError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+pub fn get_pdfium(library_path: Option<&std::path::Path>) -> Result<Pdfium, PdfiumError> {
     if let Some(p) = library_path {
         if let Ok(b) = Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(p)) {
-            return Pdfium::new(b);
+            return Ok(Pdfium::new(b));
         }
     }
-    Pdfium::new(
+    let bindings =
         Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./pdfium"))
             .or_else(|_| Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(".")))
-            .or_else(|_| Pdfium::bind_to_system_library())
-            .expect("Was unable to load pdfium. Set [config].pdfium_path or place libpdfium.so in CWD."),
-    )
+            .or_else(|_| Pdfium::bind_to_system_library())?;
+    Ok(Pdfium::new(bindings))
 }
 
 pub struct PDFComparison {
     pdfium: Arc<Pdfium>,
     render_config: PdfRenderConfig,
+    options: ComparisonOptions,
+    cache: Arc<Mutex<FingerprintCache>>,
+    overlays: bool,
 }
 
 impl PDFComparison {
-    pub fn new(pdfium: Arc<Pdfium>) -> Self {
+    pub fn new(
+        pdfium: Arc<Pdfium>,
+        options: ComparisonOptions,
+        cache: Arc<Mutex<FingerprintCache>>,
+    ) -> Self {
         let render_config = PdfRenderConfig::new()
             .set_target_width(500)
             .set_maximum_height(10000)
@@ -160,99 +424,252 @@ impl PDFComparison {
         PDFComparison {
             pdfium,
             render_config,
+            options,
+            cache,
+            overlays: false,
         }
     }
 
+    /// Also composite an onion-skin overlay for every changed page.
+    pub fn with_overlays(mut self, overlays: bool) -> Self {
+        self.overlays = overlays;
+        self
+    }
+
+    fn cache(&self) -> MutexGuard<'_, FingerprintCache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
     pub fn compare_pdfs(&self, a: &Path, b: &Path) -> Result<Vec<Comparison>, PDFComparisonError> {
         let pdf_a = self.pdfium.load_pdf_from_file(a, None);
         let pdf_b = self.pdfium.load_pdf_from_file(b, None);
         let (pdf_a, pdf_b) = match (pdf_a, pdf_b) {
             (Ok(pdf_a), Ok(pdf_b)) => (pdf_a, pdf_b),
             (Ok(pdf_a), Err(_e)) => {
-                return Ok((0..pdf_a.pages().len())
-                    .map(|_| {
-                        Comparison::Different(DifferenceSegments {
-                            segments: vec![(0., 1.)],
-                        })
-                    })
-                    .collect())
+                return (0..pdf_a.pages().len())
+                    .map(|i| Ok(Comparison::Inserted(self.inserted_page(&pdf_a, i)?)))
+                    .collect()
             }
             (Err(e), _) => return Err(PDFComparisonError::UnableToLoadPDF(e)),
         };
 
-        let n_b = pdf_b.pages().len();
-        // Render pages on demand, one at a time, comparing page A[i] against
-        // pdf_b. Peak memory is ~two page bitmaps regardless of document length
-        // — rendering every page up-front (the previous approach) OOM-killed the
-        // process on memory-constrained hosts. Slower (up to O(n*m) renders) but
-        // safe; the same-index fast path keeps the common "unchanged page" case
-        // at ~2 renders/page.
-        (0..pdf_a.pages().len())
-            .map(|i| {
-                let img_a = self.render_page(&pdf_a, i)?;
-                self.compare_page(&img_a, &pdf_b, n_b, i)
+        // Fingerprint every page (from the cache where possible; the old
+        // version usually is, as it was the new one last time), align on the
+        // fingerprints, then render at full size only the aligned pairs whose
+        // exact hashes differ. Pages are rendered one at a time, so peak
+        // memory stays at ~two page bitmaps regardless of document length,
+        // and the render count stays linear. Overlays are kept until the
+        // diff is written, so they are held as PNG rather than bitmaps.
+        let fp_a = self.fingerprints(&pdf_a, a)?;
+        let fp_b = self.fingerprints(&pdf_b, b)?;
+        let alignment = align(&fp_a, &fp_b);
+        alignment
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(i, page_match)| {
+                let i = i as u16;
+                let same_content = |j: u16| fp_a[i as usize].hash == fp_b[j as usize].hash;
+                match *page_match {
+                    PageMatch::Aligned(j) if same_content(j) => Ok(Comparison::Identical),
+                    PageMatch::Aligned(j) => match self.compare_pair(&pdf_a, i, &pdf_b, j)? {
+                        Some(segments) => Ok(Comparison::Different(segments)),
+                        None => Ok(Comparison::Identical),
+                    },
+                    PageMatch::Moved(j) => Ok(Comparison::Moved {
+                        from: j,
+                        changes: if same_content(j) {
+                            None
+                        } else {
+                            self.compare_pair(&pdf_a, i, &pdf_b, j)?
+                        },
+                    }),
+                    PageMatch::Inserted => Ok(Comparison::Inserted(self.inserted_page(&pdf_a, i)?)),
+                }
             })
+            .chain(
+                alignment
+                    .deleted
+                    .iter()
+                    .map(|j| Ok(Comparison::Deleted(*j))),
+            )
             .collect()
     }
 
-    /// Classify page `img_a` against pdf_b, rendering B pages on demand.
-    fn compare_page(
+    /// `fingerprints` for the document at `path`.
+    pub fn fingerprint_file(
         &self,
-        img_a: &RgbImage,
-        pdf_b: &PdfDocument,
-        n_b: u16,
-        same_index: u16,
-    ) -> Result<Comparison, PDFComparisonError> {
-        // Fast path: an identical page at the same index needs no full scan.
-        if same_index < n_b {
-            let img_b = self.render_page(pdf_b, same_index)?;
-            if let Similiarity::Similar(0) = PDFComparison::compare_images(img_a, &img_b) {
-                return Ok(Comparison::Identical);
+        path: &Path,
+    ) -> Result<Vec<PageFingerprint>, PDFComparisonError> {
+        let pdf = self
+            .pdfium
+            .load_pdf_from_file(path, None)
+            .map_err(PDFComparisonError::UnableToLoadPDF)?;
+        self.fingerprints(&pdf, path)
+    }
+
+    /// Fingerprint of every page of `pdf` (loaded from `path`), served from
+    /// the cache when the file's content was seen before.
+    fn fingerprints(
+        &self,
+        pdf: &PdfDocument,
+        path: &Path,
+    ) -> Result<Vec<PageFingerprint>, PDFComparisonError> {
+        let file_hash = file_hash(path)?;
+        if let Some(pages) = self.cache().get(&file_hash) {
+            if pages.len() == pdf.pages().len() as usize {
+                return Ok(pages);
             }
         }
-        // Otherwise find the most-similar B page (handles inserted/moved pages).
-        let mut best: Option<(u16, usize)> = None;
-        for j in 0..n_b {
-            let img_b = self.render_page(pdf_b, j)?;
-            if let Similiarity::Similar(c) = PDFComparison::compare_images(img_a, &img_b) {
-                if best.map_or(true, |(_, bc)| c < bc) {
-                    best = Some((j, c));
+        let pages = (0..pdf.pages().len())
+            .map(|i| Ok(PageFingerprint::from_image(&self.render_raw(pdf, i)?)))
+            .collect::<Result<Vec<_>, PDFComparisonError>>()?;
+        self.cache().insert(file_hash, pages.clone());
+        Ok(pages)
+    }
+
+    /// Changes for page `i` of `pdf_a`, which has no counterpart in the old
+    /// document.
+    fn inserted_page(
+        &self,
+        pdf_a: &PdfDocument,
+        i: u16,
+    ) -> Result<DifferenceSegments, PDFComparisonError> {
+        let mut segments = DifferenceSegments::full_page();
+        segments.text = self.text_diff(pdf_a, i, None)?;
+        if self.overlays {
+            let img_a = self.render_raw(pdf_a, i)?;
+            let (width, height) = img_a.dimensions();
+            let blank = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+            let overlay = overlay_image(&img_a, &blank, self.options.channel_tolerance);
+            let overlay = unrotate_render(pdf_a, i, overlay)?;
+            segments.overlay = Some(encode_png(&overlay)?);
+        }
+        Ok(segments)
+    }
+
+    /// Full-size comparison of page `i` of `pdf_a` against page `j` of
+    /// `pdf_b`. `None` when the pages are identical within tolerance.
+    fn compare_pair(
+        &self,
+        pdf_a: &PdfDocument,
+        i: u16,
+        pdf_b: &PdfDocument,
+        j: u16,
+    ) -> Result<Option<DifferenceSegments>, PDFComparisonError> {
+        let raw_a = self.render_raw(pdf_a, i)?;
+        let raw_b = self.render_raw(pdf_b, j)?;
+        let img_a = self.preprocess(&raw_a);
+        let img_b = self.preprocess(&raw_b);
+        let mut segments = match self.compare_images(&img_a, &img_b) {
+            Similiarity::Similar(0) => return Ok(None),
+            Similiarity::Similar(_) => match page_diff(&img_a, &img_b, &self.options) {
+                Some(mut segments) => {
+                    if self.overlays {
+                        let overlay = overlay_image(&raw_a, &raw_b, self.options.channel_tolerance);
+                        let overlay = unrotate_render(pdf_a, i, overlay)?;
+                        segments.overlay = Some(encode_png(&overlay)?);
+                    }
+                    segments
                 }
-            }
+                None => return Ok(None),
+            },
+            Similiarity::Different => DifferenceSegments::full_page(),
+        };
+        if pdf_a.pages().get(i)?.is_landscape() {
+            segments.regions = segments.regions.iter().map(Rect::unrotate_90).collect();
         }
-        match best {
-            None => Ok(Comparison::Different(DifferenceSegments {
-                segments: vec![(0., 1.)],
-            })),
-            Some((_, 0)) => Ok(Comparison::Identical),
-            Some((j, _)) => {
-                let img_b = self.render_page(pdf_b, j)?;
-                Ok(Comparison::Different(row_diff(img_a, &img_b)))
-            }
+        segments.text = self.text_diff(pdf_a, i, Some((pdf_b, j)))?;
+        segments.old_page = Some(j);
+        Ok(Some(segments))
+    }
+
+    /// Word-level diff of page `index` of `pdf_a` against its matched page
+    /// of the old document. Without a match every word counts as inserted.
+    fn text_diff(
+        &self,
+        pdf_a: &PdfDocument,
+        index: u16,
+        matched: Option<(&PdfDocument, u16)>,
+    ) -> Result<Option<TextDiff>, PDFComparisonError> {
+        if !self.options.text_diff {
+            return Ok(None);
         }
+        let words_a = extract_words(&pdf_a.pages().get(index)?)?;
+        let words_b = match matched {
+            Some((pdf_b, j)) => extract_words(&pdf_b.pages().get(j)?)?,
+            None => Vec::new(),
+        };
+        Ok(Some(diff_words(&words_a, &words_b)))
     }
 
-    fn compare_images(img_a: &RgbImage, img_b: &RgbImage) -> Similiarity {
+    /// Count changed pixels, honouring `channel_tolerance`. Counts at or
+    /// below `min_changed_pixels` are reported as `Similar(0)`.
+    fn compare_images(&self, img_a: &RgbImage, img_b: &RgbImage) -> Similiarity {
         if img_a.dimensions() != img_b.dimensions() {
             return Similiarity::Different;
         }
+        let tolerance = self.options.channel_tolerance;
         let similarity = AtomicUsize::new(0);
         (0..img_a.dimensions().0).into_par_iter().for_each(|x| {
             (0..img_a.dimensions().1).into_par_iter().for_each(|y| {
-                if img_a.get_pixel(x, y) != img_b.get_pixel(x, y) {
+                if pixel_differs(img_a.get_pixel(x, y), img_b.get_pixel(x, y), tolerance) {
                     similarity.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 }
             })
         });
-        Similiarity::Similar(similarity.into_inner())
+        match similarity.into_inner() {
+            c if c <= self.options.min_changed_pixels => Similiarity::Similar(0),
+            c => Similiarity::Similar(c),
+        }
     }
 
-    fn render_page(&self, pdf: &PdfDocument, index: u16) -> Result<RgbImage, PDFComparisonError> {
-        match pdf.pages().get(index)?.render_with_config(&self.render_config) {
+    /// Render without any of the comparison preprocessing, as fingerprinted.
+    fn render_raw(&self, pdf: &PdfDocument, index: u16) -> Result<RgbImage, PDFComparisonError> {
+        match pdf
+            .pages()
+            .get(index)?
+            .render_with_config(&self.render_config)
+        {
             Ok(bitmap) => Ok(bitmap.as_image().into_rgb8()),
             Err(e) => Err(PDFComparisonError::UnableToRenderPDF(e)),
         }
     }
+
+    /// Apply the configured preprocessing to a raw render before comparing.
+    /// The raw render is kept for the overlay, which should stay sharp.
+    fn preprocess(&self, image: &RgbImage) -> RgbImage {
+        if self.options.blur > 0. {
+            return image::imageops::blur(image, self.options.blur);
+        }
+        image.clone()
+    }
+}
+
+/// Turn a render of page `index` back upright if `rotate_if_landscape`
+/// rotated it.
+fn unrotate_render(
+    pdf: &PdfDocument,
+    index: u16,
+    image: RgbImage,
+) -> Result<RgbImage, PDFComparisonError> {
+    if pdf.pages().get(index)?.is_landscape() {
+        return Ok(image::imageops::rotate270(&image));
+    }
+    Ok(image)
+}
+
+/// Compress a render for keeping it around until the diff is written. Mostly
+/// blank pages shrink to a small fraction of the bitmap.
+fn encode_png(image: &RgbImage) -> Result<Vec<u8>, PDFComparisonError> {
+    let mut bytes = Vec::new();
+    image
+        .write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Png,
+        )
+        .map_err(PDFComparisonError::UnableToEncodeImage)?;
+    Ok(bytes)
 }
 
 #[derive(Debug)]
@@ -260,6 +677,7 @@ pub enum PDFEditorError {
     UnableToLoadPDF(PdfiumError),
     UnableToSavePDF(PdfiumError),
     UnableToModifyPDF(PdfiumError),
+    UnableToDecodeImage(image::ImageError),
     PdfiumError(PdfiumError),
 }
 
@@ -271,6 +689,7 @@ impl std::fmt::Display for PDFEditorError {
             Self::UnableToLoadPDF(e) => write!(f, "Was unable to load a pdf. Error: {}", e),
             Self::PdfiumError(e) => write!(f, "Unkown or unexpected pdfium error: {}", e),
             Self::UnableToSavePDF(e) => write!(f, "Was unable to save the pdf: {}", e),
+            Self::UnableToDecodeImage(e) => write!(f, "Was unable to decode an image: {}", e),
             Self::UnableToModifyPDF(e) => write!(
                 f,
                 "Was unable to create pdf object or modify the pdf. Error: {}",
@@ -286,32 +705,141 @@ impl From<PdfiumError> for PDFEditorError {
     }
 }
 
+/// How changed pages are presented in the generated diff PDF.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffLayout {
+    /// The new pages, with changes marked in place.
+    #[default]
+    Marked,
+    /// One wide page per change: previous version left, current right.
+    SideBySide,
+    /// The new pages replaced by an onion-skin composite with the previous
+    /// version: added ink green, removed ink red, unchanged ink grey.
+    Overlay,
+}
+
+/// Per-location context settings, read from `[config.locations.context]`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ContextOptions {
+    /// Keep this many unchanged pages before and after every changed page.
+    #[serde(default)]
+    pub pages: u16,
+    /// Keep every page of the document; only the changed ones are marked.
+    #[serde(default)]
+    pub full_document: bool,
+    /// How the kept unchanged pages are set apart from the changed ones.
+    #[serde(default)]
+    pub style: ContextStyle,
+}
+
+impl ContextOptions {
+    /// For every page of the new document (the entries of `differences`
+    /// before any `Comparison::Deleted`), whether it is an unchanged page
+    /// kept as context.
+    fn context_pages(&self, differences: &[Comparison]) -> Vec<bool> {
+        let identical: Vec<bool> = differences
+            .iter()
+            .take_while(|v| !matches!(v, Comparison::Deleted(_)))
+            .map(Comparison::is_identical)
+            .collect();
+        let reach = self.pages as usize;
+        (0..identical.len())
+            .map(|i| {
+                identical[i]
+                    && (self.full_document
+                        || (i.saturating_sub(reach)..=(i + reach).min(identical.len() - 1))
+                            .any(|n| !identical[n]))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStyle {
+    /// Fade the page behind a translucent white layer.
+    #[default]
+    Dim,
+    /// Shrink the page to half its size.
+    Downscale,
+}
+
+/// Scale factor applied to context pages with `ContextStyle::Downscale`.
+const CONTEXT_DOWNSCALE: f32 = 0.5;
+
 pub struct PDFEditor {
     pdfium: Arc<Pdfium>,
+    layout: DiffLayout,
+    context: ContextOptions,
 }
 
 impl PDFEditor {
-    pub fn new(pdfium: Arc<Pdfium>) -> Self {
-        PDFEditor { pdfium }
+    pub fn new(pdfium: Arc<Pdfium>, layout: DiffLayout, context: ContextOptions) -> Self {
+        PDFEditor {
+            pdfium,
+            layout,
+            context,
+        }
     }
 
+    /// Write the diff of `in_path` to `out_path`. `old_path` is the previous
+    /// version, from which `Comparison::Deleted` pages are copied.
     pub fn mark_differences(
         &self,
         in_path: &Path,
+        old_path: &Path,
         differences: &[Comparison],
         out_path: &Path,
+    ) -> Result<(), PDFEditorError> {
+        match self.layout {
+            DiffLayout::Marked | DiffLayout::Overlay => {
+                let since = previous_version_time(old_path);
+                self.mark_in_place(in_path, old_path, differences, out_path, since)
+            }
+            DiffLayout::SideBySide => {
+                self.mark_side_by_side(in_path, old_path, differences, out_path)
+            }
+        }
+    }
+
+    fn mark_in_place(
+        &self,
+        in_path: &Path,
+        old_path: &Path,
+        differences: &[Comparison],
+        out_path: &Path,
+        since: Option<DateTime<Local>>,
     ) -> Result<(), PDFEditorError> {
         let mut pdf = match self.pdfium.load_pdf_from_file(in_path, None) {
             Ok(v) => v,
             Err(e) => return Err(PDFEditorError::UnableToLoadPDF(e)),
         };
+        let old_pdf = if differences
+            .iter()
+            .any(|v| matches!(v, Comparison::Deleted(_)))
+        {
+            match self.pdfium.load_pdf_from_file(old_path, None) {
+                Ok(v) => Some(v),
+                Err(e) => return Err(PDFEditorError::UnableToLoadPDF(e)),
+            }
+        } else {
+            None
+        };
 
         let mut page_shift: i16 = 0;
+        let font = pdf.fonts_mut().helvetica_bold();
+        let context_pages = self.context.context_pages(differences);
 
         differences
             .iter()
             .enumerate()
             .try_for_each(|(index, difference)| match difference {
+                Comparison::Identical if context_pages[index] => {
+                    let mut p = pdf.pages_mut().get((index as i16 + page_shift) as u16)?;
+                    self.mark_context_page(&mut p)?;
+                    stamp_page_number(&mut p, index as u16, font)
+                }
                 Comparison::Identical => {
                     let _ = pdf
                         .pages_mut()
@@ -320,10 +848,38 @@ impl PDFEditor {
                     page_shift -= 1;
                     Ok::<(), PDFEditorError>(())
                 }
-                Comparison::Different(seg) => {
+                Comparison::Different(seg) | Comparison::Inserted(seg) => {
+                    let mut p = pdf.pages_mut().get((index as i16 + page_shift) as u16)?;
+                    self.mark_page_differences(&pdf, &mut p, seg, since)?;
+                    stamp_page_number(&mut p, index as u16, font)
+                }
+                Comparison::Moved { from, changes } => {
                     let mut p = pdf.pages_mut().get((index as i16 + page_shift) as u16)?;
-                    self.mark_page_differences(&pdf, &mut p, seg)?;
-                    Ok(())
+                    if let Some(seg) = changes {
+                        self.mark_page_differences(&pdf, &mut p, seg, since)?;
+                    }
+                    stamp_page_number(&mut p, index as u16, font)?;
+                    stamp_label(
+                        &mut p,
+                        PdfPoints::ZERO,
+                        &format!("Moved from page {}", from + 1),
+                        font,
+                        PdfColor::new(0, 90, 200, 255),
+                    )
+                }
+                Comparison::Deleted(old_index) => {
+                    let Some(old_pdf) = &old_pdf else {
+                        return Ok(());
+                    };
+                    let index = pdf.pages().len();
+                    if let Err(e) = pdf
+                        .pages_mut()
+                        .copy_page_from_document(old_pdf, *old_index, index)
+                    {
+                        return Err(PDFEditorError::UnableToModifyPDF(e));
+                    }
+                    let mut p = pdf.pages_mut().get(index)?;
+                    self.mark_page_removed(&mut p, *old_index, font)
                 }
             })?;
 
@@ -334,32 +890,294 @@ impl PDFEditor {
         Ok(())
     }
 
+    /// Build a new document with one wide page per change, the old page on
+    /// the left and the new page on the right.
+    fn mark_side_by_side(
+        &self,
+        in_path: &Path,
+        old_path: &Path,
+        differences: &[Comparison],
+        out_path: &Path,
+    ) -> Result<(), PDFEditorError> {
+        let new_pdf = match self.pdfium.load_pdf_from_file(in_path, None) {
+            Ok(v) => v,
+            Err(e) => return Err(PDFEditorError::UnableToLoadPDF(e)),
+        };
+        // A brand-new file has no previous version; its pages get an empty left half.
+        let old_pdf = self.pdfium.load_pdf_from_file(old_path, None).ok();
+        let mut out = match self.pdfium.create_new_pdf() {
+            Ok(v) => v,
+            Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+        };
+        let font = out.fonts_mut().helvetica_bold();
+
+        for (index, difference) in differences.iter().enumerate() {
+            let index = index as u16;
+            let (old_index, new_index, segments) = match difference {
+                Comparison::Identical => continue,
+                Comparison::Different(seg) => (seg.old_page, Some(index), Some(seg)),
+                Comparison::Inserted(seg) => (None, Some(index), Some(seg)),
+                Comparison::Moved { from, changes } => (Some(*from), Some(index), changes.as_ref()),
+                Comparison::Deleted(old_index) => (Some(*old_index), None, None),
+            };
+            let old = old_index.and_then(|j| Some((old_pdf.as_ref()?, j)));
+            let new = new_index.map(|i| (&new_pdf, i));
+            self.append_side_by_side(&mut out, old, new, segments, font)?;
+        }
+
+        if let Err(e) = out.save_to_file(out_path) {
+            return Err(PDFEditorError::UnableToSavePDF(e));
+        }
+        Ok(())
+    }
+
+    /// Tile `old` and `new` (either may be missing) onto one page appended
+    /// to `out` and highlight `segments` on both halves.
+    fn append_side_by_side<'a>(
+        &self,
+        out: &mut PdfDocument,
+        old: Option<(&PdfDocument<'a>, u16)>,
+        new: Option<(&PdfDocument<'a>, u16)>,
+        segments: Option<&DifferenceSegments>,
+        font: PdfFontToken,
+    ) -> Result<(), PDFEditorError> {
+        let page_size = |side: Option<(&PdfDocument<'a>, u16)>| -> Result<_, PDFEditorError> {
+            Ok(match side {
+                Some((doc, i)) => {
+                    let page = doc.pages().get(i)?;
+                    Some((page.width(), page.height()))
+                }
+                None => None,
+            })
+        };
+        let old_size = page_size(old)?;
+        let new_size = page_size(new)?;
+        let (cell_width, cell_height) = match (old_size, new_size) {
+            (Some((ow, oh)), Some((nw, nh))) => (
+                PdfPoints::new(ow.value.max(nw.value)),
+                PdfPoints::new(oh.value.max(nh.value)),
+            ),
+            (Some(size), None) | (None, Some(size)) => size,
+            (None, None) => return Ok(()),
+        };
+
+        let mut pair = match self.pdfium.create_new_pdf() {
+            Ok(v) => v,
+            Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+        };
+        for side in [old, new] {
+            let result = match side {
+                Some((doc, i)) => {
+                    let at = pair.pages().len();
+                    pair.pages_mut().copy_page_from_document(doc, i, at)
+                }
+                None => pair
+                    .pages_mut()
+                    .create_page_at_end(PdfPagePaperSize::Custom(cell_width, cell_height))
+                    .map(|_| ()),
+            };
+            result.map_err(PDFEditorError::UnableToModifyPDF)?;
+        }
+        let tiled = match pair.pages().tile_into_new_document(
+            1,
+            2,
+            PdfPagePaperSize::Custom(cell_width * 2., cell_height),
+        ) {
+            Ok(v) => v,
+            Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+        };
+        let at = out.pages().len();
+        if let Err(e) = out.pages_mut().copy_page_from_document(&tiled, 0, at) {
+            return Err(PDFEditorError::UnableToModifyPDF(e));
+        }
+        let mut page = out.pages_mut().get(at)?;
+
+        // pdfium scales each page to fit its cell and centres it; mirror that
+        // to map page fractions onto the combined page.
+        let placement = |size: Option<(PdfPoints, PdfPoints)>, cell_x: f32| {
+            size.map(|(w, h)| {
+                let scale = (cell_width.value / w.value).min(cell_height.value / h.value);
+                let x = cell_x + (cell_width.value - w.value * scale) / 2.;
+                let y = (cell_height.value - h.value * scale) / 2.;
+                move |r: &Rect| {
+                    PdfRect::new_from_values(
+                        y + (1. - r.bottom as f32) * h.value * scale,
+                        x + r.left as f32 * w.value * scale,
+                        y + (1. - r.top as f32) * h.value * scale,
+                        x + r.right as f32 * w.value * scale,
+                    )
+                }
+            })
+        };
+        let left = placement(old_size, 0.);
+        let right = placement(new_size, cell_width.value);
+
+        let red = PdfColor::new(220, 0, 0, 255);
+        let green = PdfColor::new(0, 160, 0, 255);
+        if let Some(segments) = segments {
+            for rect in &segments.regions {
+                for place in [&left, &right].into_iter().flatten() {
+                    draw_box(&mut page, place(rect), red)?;
+                }
+            }
+            if let Some(text) = &segments.text {
+                if let Some(place) = &left {
+                    for rect in text.deleted.iter().flat_map(|run| run.bounds.iter()) {
+                        draw_box(&mut page, place(rect), red)?;
+                    }
+                }
+                if let Some(place) = &right {
+                    for rect in text.inserted.iter().flat_map(|run| run.bounds.iter()) {
+                        draw_box(&mut page, place(rect), green)?;
+                    }
+                }
+            }
+        }
+
+        let grey = PdfColor::new(90, 90, 90, 255);
+        let old_label = match old {
+            Some((_, j)) => format!("Previous (page {})", j + 1),
+            None => "Previous (none)".to_string(),
+        };
+        let new_label = match new {
+            Some((_, i)) => format!("Current (page {})", i + 1),
+            None => "Current (removed)".to_string(),
+        };
+        stamp_label(&mut page, PdfPoints::ZERO, &old_label, font, grey)?;
+        stamp_label(&mut page, cell_width, &new_label, font, grey)
+    }
+
+    /// Set an unchanged page kept for context apart from the changed ones.
+    fn mark_context_page(&self, page: &mut PdfPage) -> Result<(), PDFEditorError> {
+        match self.context.style {
+            ContextStyle::Dim => {
+                let bounds = PdfRect::new(
+                    PdfPoints::ZERO,
+                    PdfPoints::ZERO,
+                    page.height(),
+                    page.width(),
+                );
+                match page.objects_mut().create_path_object_rect(
+                    bounds,
+                    None,
+                    None,
+                    Some(PdfColor::new(255, 255, 255, 150)),
+                ) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(PDFEditorError::UnableToModifyPDF(e)),
+                }
+            }
+            ContextStyle::Downscale => {
+                let media = page.boundaries().media()?.bounds;
+                let scaled = PdfRect::new_from_values(
+                    media.bottom().value * CONTEXT_DOWNSCALE,
+                    media.left().value * CONTEXT_DOWNSCALE,
+                    media.top().value * CONTEXT_DOWNSCALE,
+                    media.right().value * CONTEXT_DOWNSCALE,
+                );
+                page.scale(CONTEXT_DOWNSCALE, CONTEXT_DOWNSCALE)
+                    .and_then(|_| page.boundaries_mut().set_media(scaled))
+                    .and_then(|_| page.boundaries_mut().set_crop(scaled))
+                    .map_err(PDFEditorError::UnableToModifyPDF)
+            }
+        }
+    }
+
+    /// Tint and frame a page copied from the old document and label it with
+    /// its former position.
+    fn mark_page_removed(
+        &self,
+        page: &mut PdfPage,
+        old_index: u16,
+        font: PdfFontToken,
+    ) -> Result<(), PDFEditorError> {
+        let bounds = PdfRect::new(
+            PdfPoints::ZERO,
+            PdfPoints::ZERO,
+            page.height(),
+            page.width(),
+        );
+        if let Err(e) = page.objects_mut().create_path_object_rect(
+            bounds,
+            Some(PdfColor::new(220, 0, 0, 255)),
+            Some(PdfPoints::new(8.)),
+            Some(PdfColor::new(220, 0, 0, 40)),
+        ) {
+            return Err(PDFEditorError::UnableToModifyPDF(e));
+        }
+        stamp_label(
+            page,
+            PdfPoints::ZERO,
+            &format!("Removed (was page {})", old_index + 1),
+            font,
+            PdfColor::new(220, 0, 0, 255),
+        )
+    }
+
     fn mark_page_differences<'a>(
         &self,
         doc: &PdfDocument<'a>,
         page: &mut PdfPage<'a>,
         segments: &DifferenceSegments,
+        since: Option<DateTime<Local>>,
     ) -> Result<(), PDFEditorError> {
-        let image_width = page.width().value as u32 * 5;
-        let image_height = page.height().value as u32 * 5;
-
-        let mut buffer = RgbaImage::new(image_width, image_height);
-
-        segments.segments.iter().for_each(|(start, end)| {
-            (((image_height as f64 * *start).floor() as u32)
-                ..(image_height as f64 * *end).floor() as u32)
-                .for_each(|row| {
-                    (0..10.min(image_width)).for_each(|column| {
-                        buffer.put_pixel(column, row, Rgba([255, 0, 0, 255]));
-                    });
-                });
-        });
+        if let Some(overlay) = &segments.overlay {
+            self.cover_page(doc, page, overlay)?;
+        }
 
-        let object = match PdfPageImageObject::new_with_height(doc, &buffer.into(), page.height()) {
-            Ok(v) => v,
-            Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+        let since = since.map(|t| t.format("%Y-%m-%d %H:%M").to_string());
+        let comment = match (&since, segments.old_page) {
+            (None, _) => "New document".to_string(),
+            (Some(since), None) => format!("Page added since {}", since),
+            (Some(since), Some(_)) => format!("Changed since {}", since),
         };
+        for rect in &segments.regions {
+            annotate_region(page, rect, PdfColor::new(220, 0, 0, 255), &comment)?;
+        }
+        if let Some(text) = &segments.text {
+            for run in &text.inserted {
+                let comment = match &since {
+                    Some(since) => format!("Added since {}: \"{}\"", since, run.text),
+                    None => format!("Added: \"{}\"", run.text),
+                };
+                highlight_run(page, &run.bounds, PdfColor::new(0, 200, 0, 255), &comment)?;
+            }
+        }
 
+        if let Some(text) = segments.text.as_ref().filter(|t| !t.is_empty()) {
+            let page_height = page.height();
+            let mut note = match page
+                .annotations_mut()
+                .create_text_annotation(&text.summary())
+            {
+                Ok(v) => v,
+                Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+            };
+            note.set_bounds(PdfRect::new(
+                page_height - PdfPoints::new(24.),
+                PdfPoints::new(12.),
+                page_height,
+                PdfPoints::new(36.),
+            ))
+            .map_err(PDFEditorError::UnableToModifyPDF)?;
+        }
+        Ok(())
+    }
+
+    /// Lay an opaque onion-skin composite (PNG) over the whole page.
+    fn cover_page<'a>(
+        &self,
+        doc: &PdfDocument<'a>,
+        page: &mut PdfPage<'a>,
+        overlay: &[u8],
+    ) -> Result<(), PDFEditorError> {
+        let image = image::load_from_memory_with_format(overlay, image::ImageFormat::Png)
+            .map_err(PDFEditorError::UnableToDecodeImage)?;
+        let object =
+            match PdfPageImageObject::new_with_size(doc, &image, page.width(), page.height()) {
+                Ok(v) => v,
+                Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+            };
         if let Err(e) = page.objects_mut().add_image_object(object) {
             return Err(PDFEditorError::UnableToModifyPDF(e));
         }
@@ -367,3 +1185,318 @@ impl PDFEditor {
     }
 }
 
+/// Write `text` near the bottom of `page`, `x` from its left edge.
+fn stamp_label(
+    page: &mut PdfPage,
+    x: PdfPoints,
+    text: &str,
+    font: PdfFontToken,
+    color: PdfColor,
+) -> Result<(), PDFEditorError> {
+    let mut label = match page.objects_mut().create_text_object(
+        x + PdfPoints::new(12.),
+        PdfPoints::new(12.),
+        text,
+        font,
+        PdfPoints::new(14.),
+    ) {
+        Ok(v) => v,
+        Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+    };
+    label
+        .set_fill_color(color)
+        .map_err(PDFEditorError::UnableToModifyPDF)
+}
+
+/// Label a kept page with its position in the new document, bottom right.
+fn stamp_page_number(
+    page: &mut PdfPage,
+    index: u16,
+    font: PdfFontToken,
+) -> Result<(), PDFEditorError> {
+    let x = page.width() - PdfPoints::new(90.);
+    stamp_label(
+        page,
+        x,
+        &format!("Page {}", index + 1),
+        font,
+        PdfColor::new(90, 90, 90, 255),
+    )
+}
+
+/// Stroke `bounds` (page points) as a vector rectangle on `page`.
+fn draw_box(page: &mut PdfPage, bounds: PdfRect, color: PdfColor) -> Result<(), PDFEditorError> {
+    match page.objects_mut().create_path_object_rect(
+        bounds,
+        Some(color),
+        Some(PdfPoints::new(1.5)),
+        None,
+    ) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(PDFEditorError::UnableToModifyPDF(e)),
+    }
+}
+
+/// Modification time of the previous version, i.e. when it was last
+/// compared against. `None` for a file without a previous version.
+fn previous_version_time(old_path: &Path) -> Option<DateTime<Local>> {
+    let modified = std::fs::metadata(old_path).ok()?.modified().ok()?;
+    Some(modified.into())
+}
+
+/// Frame `rect` (page fractions) with a square annotation carrying `comment`.
+fn annotate_region(
+    page: &mut PdfPage,
+    rect: &Rect,
+    color: PdfColor,
+    comment: &str,
+) -> Result<(), PDFEditorError> {
+    let bounds = rect.to_pdf_rect(page.width(), page.height());
+    let mut annotation = match page.annotations_mut().create_square_annotation() {
+        Ok(v) => v,
+        Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+    };
+    annotation
+        .set_bounds(bounds)
+        .and_then(|_| annotation.set_stroke_color(color))
+        .and_then(|_| annotation.set_contents(comment))
+        .and_then(|_| annotation.set_creation_date(Utc::now()))
+        .map_err(PDFEditorError::UnableToModifyPDF)
+}
+
+/// Highlight the lines of a text run (page fractions) with `comment`.
+fn highlight_run(
+    page: &mut PdfPage,
+    lines: &[Rect],
+    color: PdfColor,
+    comment: &str,
+) -> Result<(), PDFEditorError> {
+    let Some(first) = lines.first() else {
+        return Ok(());
+    };
+    let (width, height) = (page.width(), page.height());
+    let bounds = lines.iter().fold(*first, |acc, r| acc.union(r));
+    let mut annotation = match page.annotations_mut().create_highlight_annotation() {
+        Ok(v) => v,
+        Err(e) => return Err(PDFEditorError::UnableToModifyPDF(e)),
+    };
+    for line in lines {
+        let quad = PdfQuadPoints::from_rect(&line.to_pdf_rect(width, height));
+        if let Err(e) = annotation
+            .attachment_points_mut()
+            .create_attachment_point_at_end(quad)
+        {
+            return Err(PDFEditorError::UnableToModifyPDF(e));
+        }
+    }
+    annotation
+        .set_bounds(bounds.to_pdf_rect(width, height))
+        .and_then(|_| annotation.set_stroke_color(color))
+        .and_then(|_| annotation.set_contents(comment))
+        .and_then(|_| annotation.set_creation_date(Utc::now()))
+        .map_err(PDFEditorError::UnableToModifyPDF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// White render with black pixels at `ink`.
+    fn render(width: u32, height: u32, ink: &[(u32, u32)]) -> RgbImage {
+        let mut img = RgbImage::from_pixel(width, height, Rgb([255; 3]));
+        for (x, y) in ink {
+            img.put_pixel(*x, *y, Rgb([0; 3]));
+        }
+        img
+    }
+
+    /// Mask of a 64x64 render with black pixels at `ink` against a blank one.
+    fn mask(ink: &[(u32, u32)]) -> DiffMask {
+        DiffMask::new(&render(64, 64, ink), &render(64, 64, &[]), 0)
+    }
+
+    fn rect(left: u32, top: u32, right: u32, bottom: u32) -> Rect {
+        Rect {
+            left: left as f64 / 64.,
+            top: top as f64 / 64.,
+            right: right as f64 / 64.,
+            bottom: bottom as f64 / 64.,
+        }
+    }
+
+    #[test]
+    fn distant_changes_are_separate_regions() {
+        let mask = mask(&[(2, 2), (3, 3), (50, 50)]);
+        assert_eq!(
+            region_diff(&mask, &mask),
+            vec![((2, 2), rect(2, 2, 4, 4)), ((1, 1), rect(50, 50, 51, 51))]
+        );
+    }
+
+    #[test]
+    fn diagonally_touching_tiles_form_one_region() {
+        let mask = mask(&[(7, 0), (8, 9)]);
+        assert_eq!(
+            region_diff(&mask, &mask),
+            vec![((2, 10), rect(7, 0, 9, 10))]
+        );
+    }
+
+    #[test]
+    fn unchanged_renders_have_no_regions() {
+        let mask = mask(&[]);
+        assert!(region_diff(&mask, &mask).is_empty());
+    }
+
+    #[test]
+    fn unrotate_90_maps_back_onto_the_upright_page() {
+        let rotated = Rect {
+            left: 0.125,
+            top: 0.25,
+            right: 0.5,
+            bottom: 0.75,
+        };
+        assert_eq!(
+            rotated.unrotate_90(),
+            Rect {
+                left: 0.25,
+                top: 0.5,
+                right: 0.75,
+                bottom: 0.875,
+            }
+        );
+        assert_eq!(Rect::FULL.unrotate_90(), Rect::FULL);
+    }
+
+    #[test]
+    fn channel_tolerance_absorbs_small_differences() {
+        let a = RgbImage::from_pixel(2, 1, Rgb([100, 100, 100]));
+        let b = RgbImage::from_pixel(2, 1, Rgb([110, 100, 100]));
+        assert_eq!(DiffMask::new(&a, &b, 10).changed, vec![false, false]);
+        assert_eq!(DiffMask::new(&a, &b, 9).changed, vec![true, true]);
+    }
+
+    #[test]
+    fn dilate_grows_changes_into_squares_clipped_to_the_render() {
+        let mask = mask(&[(5, 5), (0, 63)]);
+        assert_eq!(mask.dilate(0).changed, mask.changed);
+        let dilated = mask.dilate(1);
+        let changed: Vec<(u32, u32)> = (0..64)
+            .flat_map(|y| (0..64).map(move |x| (x, y)))
+            .filter(|(x, y)| dilated.get(*x, *y))
+            .collect();
+        let mut expected: Vec<(u32, u32)> = (4..=6)
+            .flat_map(|y| (4..=6).map(move |x| (x, y)))
+            .chain([(0, 62), (1, 62), (0, 63), (1, 63)])
+            .collect();
+        expected.sort_by_key(|(x, y)| (*y, *x));
+        assert_eq!(changed, expected);
+    }
+
+    #[test]
+    fn regions_below_min_region_size_are_noise() {
+        let mut ink: Vec<(u32, u32)> = (40..44)
+            .flat_map(|y| (40..44).map(move |x| (x, y)))
+            .collect();
+        ink.push((2, 2));
+        let old = render(64, 64, &[]);
+        let new = render(64, 64, &ink);
+        let options = ComparisonOptions {
+            min_region_size: 3,
+            ..ComparisonOptions::default()
+        };
+        let diff = page_diff(&new, &old, &options).unwrap();
+        assert_eq!(diff.regions, vec![rect(40, 40, 44, 44)]);
+
+        let options = ComparisonOptions {
+            min_region_size: 5,
+            ..ComparisonOptions::default()
+        };
+        assert!(page_diff(&new, &old, &options).is_none());
+    }
+
+    #[test]
+    fn dilation_joins_nearby_changes_into_one_region() {
+        let old = render(64, 64, &[]);
+        let new = render(64, 64, &[(2, 2), (20, 2)]);
+        let diff = page_diff(&new, &old, &ComparisonOptions::default()).unwrap();
+        assert_eq!(diff.regions, vec![rect(2, 2, 3, 3), rect(20, 2, 21, 3)]);
+
+        let options = ComparisonOptions {
+            dilate: 8,
+            ..ComparisonOptions::default()
+        };
+        let diff = page_diff(&new, &old, &options).unwrap();
+        assert_eq!(diff.regions, vec![rect(2, 2, 21, 3)]);
+    }
+
+    #[test]
+    fn overlay_colours_added_removed_and_unchanged_ink() {
+        let pixels =
+            |p: &[[u8; 3]]| RgbImage::from_fn(p.len() as u32, 1, |x, _| Rgb(p[x as usize]));
+        let (white, black, grey) = ([255; 3], [0; 3], [100; 3]);
+        let new = pixels(&[white, black, black, white, black]);
+        let old = pixels(&[white, black, white, black, grey]);
+        let overlay = overlay_image(&new, &old, 0);
+        assert_eq!(
+            overlay.pixels().map(|p| p.0).collect::<Vec<_>>(),
+            vec![
+                // Paper on both.
+                [255, 255, 255],
+                // Same ink on both, greyed out.
+                [128, 128, 128],
+                // Added, green.
+                [0, 170, 0],
+                // Removed, red.
+                [192, 0, 0],
+                // Changed ink, the new stroke wins.
+                [0, 170, 0],
+            ]
+        );
+    }
+
+    /// `Identical` for `.`, `Different` for `x`, `Deleted` for `-`.
+    fn comparisons(pattern: &str) -> Vec<Comparison> {
+        pattern
+            .chars()
+            .map(|c| match c {
+                '.' => Comparison::Identical,
+                'x' => Comparison::Different(DifferenceSegments::default()),
+                _ => Comparison::Deleted(0),
+            })
+            .collect()
+    }
+
+    fn context(pages: u16, full_document: bool, pattern: &str) -> Vec<bool> {
+        let options = ContextOptions {
+            pages,
+            full_document,
+            ..ContextOptions::default()
+        };
+        options.context_pages(&comparisons(pattern))
+    }
+
+    #[test]
+    fn context_pages_surround_changes() {
+        assert_eq!(
+            context(1, false, "...x..."),
+            vec![false, false, true, false, true, false, false]
+        );
+        assert_eq!(context(0, false, ".x."), vec![false; 3]);
+    }
+
+    #[test]
+    fn context_pages_stop_at_the_document_edges() {
+        assert_eq!(context(1, false, "x.."), vec![false, true, false]);
+        assert_eq!(context(1, false, "..x"), vec![false, true, false]);
+        assert_eq!(context(5, false, "x.."), vec![false, true, true]);
+        // Deleted pages follow the new document's pages and get no entry.
+        assert_eq!(context(1, false, ".x--"), vec![true, false]);
+        assert_eq!(context(1, false, "..--"), vec![false, false]);
+    }
+
+    #[test]
+    fn full_document_keeps_every_unchanged_page() {
+        assert_eq!(context(0, true, ".x.."), vec![true, false, true, true]);
+    }
+}
@@ -0,0 +1,85 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Serialized contents of an index (fingerprint cache, state index or
+/// version index), taken while its lock is held and written once the lock
+/// is released, so neither the executor nor the pdf workers wait on disk.
+#[derive(Debug)]
+pub struct PendingWrite {
+    path: PathBuf,
+    bytes: Vec<u8>,
+}
+
+impl PendingWrite {
+    pub fn new(path: PathBuf, bytes: Vec<u8>) -> Self {
+        PendingWrite { path, bytes }
+    }
+
+    /// Write the file on a blocking thread.
+    pub async fn write(self) -> io::Result<()> {
+        tokio::task::spawn_blocking(move || {
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            write_atomic(&self.path, &self.bytes)
+        })
+        .await?
+    }
+}
+
+/// Write `pending`, as returned by an index's `pending_write`, if there is
+/// anything to write.
+pub async fn write_pending(pending: io::Result<Option<PendingWrite>>) -> io::Result<()> {
+    match pending? {
+        Some(pending) => pending.write().await,
+        None => Ok(()),
+    }
+}
+
+/// Replace the file at `path` with `bytes`. They are written to a sibling
+/// `.tmp` file first and renamed over `path` once on disk, so a crash or a
+/// full disk leaves either the old or the new contents, never a torn file.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    let written = File::create(&tmp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| std::fs::rename(&tmp, path)) {
+        std::fs::remove_file(&tmp).ok();
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_atomic_replaces_the_file_and_leaves_no_tmp() {
+        let dir = std::env::temp_dir().join(format!("persist-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index.json");
+        std::fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(!dir.join("index.json.tmp").exists());
+
+        // The directory is missing, so nothing is written or left behind.
+        let missing = dir.join("missing/index.json");
+        assert!(write_atomic(&missing, b"new").is_err());
+        assert!(!missing.exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
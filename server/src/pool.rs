@@ -0,0 +1,174 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+/// Runs blocking pdfium work on tokio's blocking threads, at most `workers`
+/// jobs at a time. Further jobs wait in line, so large comparisons never
+/// occupy the async executor that also serves HTTP requests.
+#[derive(Debug)]
+pub struct WorkerPool {
+    workers: usize,
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    running: Arc<AtomicUsize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStatus {
+    pub workers: usize,
+    /// Jobs waiting for a free worker.
+    pub queued: usize,
+    pub running: usize,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+        WorkerPool {
+            workers,
+            permits: Arc::new(Semaphore::new(workers)),
+            queued: AtomicUsize::new(0),
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Run `job` once a worker is free and wait for its result.
+    pub async fn run<T, F>(&self, job: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let permit = {
+            let _queued = Counted::new(&self.queued);
+            self.permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("worker pool semaphore is never closed")
+        };
+        let running = self.running.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _running = Counted::new(&running);
+            job()
+        });
+        match handle.await {
+            Ok(v) => v,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Run `job`, which does its blocking work elsewhere (e.g. in another
+    /// process), under the same limit as `run`.
+    pub async fn limit<T>(&self, job: impl Future<Output = T>) -> T {
+        let _permit = {
+            let _queued = Counted::new(&self.queued);
+            self.permits
+                .acquire()
+                .await
+                .expect("worker pool semaphore is never closed")
+        };
+        let _running = Counted::new(&self.running);
+        job.await
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            workers: self.workers,
+            queued: self.queued.load(Ordering::Relaxed),
+            running: self.running.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Increments a counter for as long as it is alive, also when the job
+/// panics or the waiting future is dropped.
+struct Counted<'a>(&'a AtomicUsize);
+
+impl<'a> Counted<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Counted(counter)
+    }
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Wait until the pool's status satisfies `done`.
+    async fn wait_for(pool: &WorkerPool, done: impl Fn(&PoolStatus) -> bool) {
+        for _ in 0..1000 {
+            if done(&pool.status()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("pool stuck at {:?}", pool.status());
+    }
+
+    #[tokio::test]
+    async fn jobs_beyond_the_limit_wait_in_line() {
+        let pool = Arc::new(WorkerPool::new(2));
+        let release = Arc::new(AtomicBool::new(false));
+        let jobs: Vec<_> = (0..3)
+            .map(|k| {
+                let (pool, release) = (pool.clone(), release.clone());
+                tokio::spawn(async move {
+                    pool.run(move || {
+                        while !release.load(Ordering::Relaxed) {
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                        k
+                    })
+                    .await
+                })
+            })
+            .collect();
+        wait_for(&pool, |s| s.running == 2 && s.queued == 1).await;
+        assert_eq!(pool.status().workers, 2);
+
+        release.store(true, Ordering::Relaxed);
+        let mut results = Vec::new();
+        for job in jobs {
+            results.push(job.await.unwrap());
+        }
+        assert_eq!(results, vec![0, 1, 2]);
+        let status = pool.status();
+        assert_eq!((status.queued, status.running), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn limited_jobs_count_against_the_same_limit() {
+        let pool = Arc::new(WorkerPool::new(1));
+        let release = Arc::new(tokio::sync::Notify::new());
+        let running = {
+            let (pool, release) = (pool.clone(), release.clone());
+            tokio::spawn(async move { pool.limit(release.notified()).await })
+        };
+        wait_for(&pool, |s| s.running == 1).await;
+        let waiting = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.run(|| 1).await })
+        };
+        wait_for(&pool, |s| s.queued == 1).await;
+
+        release.notify_one();
+        running.await.unwrap();
+        assert_eq!(waiting.await.unwrap(), 1);
+        let status = pool.status();
+        assert_eq!((status.queued, status.running), (0, 0));
+    }
+}
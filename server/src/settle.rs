@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Delay before the first retry of a failed file; doubled per attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+/// Upper bound for the retry delay.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Size and mtime of a file when it was looked at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+struct Failure {
+    snapshot: Snapshot,
+    attempts: u32,
+    /// `None` once the retries are used up: the file is left alone until
+    /// it changes.
+    next_try: Option<Instant>,
+}
+
+/// Holds back files that are still being written and files that failed to
+/// process, so half-uploaded PDFs are neither diffed nor reported.
+#[derive(Debug)]
+pub struct Settler {
+    settle_time: Duration,
+    max_retries: u32,
+    /// Files seen changing, with when they were first seen in this state.
+    unsettled: HashMap<PathBuf, (Snapshot, Instant)>,
+    failures: HashMap<PathBuf, Failure>,
+}
+
+impl Settler {
+    pub fn new(settle_time: Duration, max_retries: u32) -> Self {
+        Settler {
+            settle_time,
+            max_retries,
+            unsettled: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Whether the file may be processed now: its size and mtime have been
+    /// stable for the settle time, and it is not waiting for a retry.
+    pub fn ready(&mut self, path: &Path, snapshot: Snapshot) -> bool {
+        if let Some(failure) = self.failures.get(path) {
+            if failure.snapshot == snapshot {
+                return failure.next_try.is_some_and(|t| Instant::now() >= t);
+            }
+            // Rewritten since it failed; start over.
+            self.failures.remove(path);
+        }
+        let modified_long_ago = snapshot
+            .modified
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age >= self.settle_time);
+        if modified_long_ago {
+            self.unsettled.remove(path);
+            return true;
+        }
+        match self.unsettled.get(path) {
+            Some((seen, since)) if *seen == snapshot => {
+                if since.elapsed() < self.settle_time {
+                    return false;
+                }
+                self.unsettled.remove(path);
+                true
+            }
+            _ => {
+                self.unsettled
+                    .insert(path.to_path_buf(), (snapshot, Instant::now()));
+                false
+            }
+        }
+    }
+
+    pub fn succeeded(&mut self, path: &Path) {
+        self.failures.remove(path);
+    }
+
+    /// Drop a file that no longer exists.
+    pub fn forget(&mut self, path: &Path) {
+        self.unsettled.remove(path);
+        self.failures.remove(path);
+    }
+
+    /// Record a failed attempt and schedule the next one. Returns whether
+    /// the error should be reported, which happens once all retries are
+    /// used up; the file is then not retried until it changes.
+    pub fn failed(&mut self, path: &Path, snapshot: Snapshot) -> bool {
+        let failure = self.failures.entry(path.to_path_buf()).or_insert(Failure {
+            snapshot,
+            attempts: 0,
+            next_try: None,
+        });
+        failure.attempts += 1;
+        if failure.attempts > self.max_retries {
+            failure.next_try = None;
+            return failure.attempts == self.max_retries + 1;
+        }
+        let delay = RETRY_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(failure.attempts - 1))
+            .min(RETRY_MAX_DELAY);
+        failure.next_try = Some(Instant::now() + delay);
+        false
+    }
+
+    fn retrying(&self) -> impl Iterator<Item = &PathBuf> {
+        self.failures
+            .iter()
+            .filter(|(_, failure)| failure.next_try.is_some())
+            .map(|(path, _)| path)
+    }
+
+    /// Files to look at again on the next poll even without new events.
+    pub fn waiting(&self) -> Vec<PathBuf> {
+        self.unsettled
+            .keys()
+            .chain(self.retrying())
+            .cloned()
+            .collect()
+    }
+
+    /// Files waiting for their size and mtime to settle.
+    pub fn settling(&self) -> Vec<PathBuf> {
+        self.unsettled.keys().cloned().collect()
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        !self.unsettled.is_empty() || self.retrying().next().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(size: u64, age: Duration) -> Snapshot {
+        Snapshot {
+            size,
+            modified: Some(SystemTime::now() - age),
+        }
+    }
+
+    #[test]
+    fn files_modified_long_ago_are_ready() {
+        let mut settler = Settler::new(Duration::from_secs(10), 3);
+        let path = Path::new("a.pdf");
+        assert!(settler.ready(path, snapshot(1, Duration::from_secs(60))));
+        assert!(!settler.is_waiting());
+    }
+
+    #[test]
+    fn fresh_files_wait_until_unchanged() {
+        let mut settler = Settler::new(Duration::from_secs(60), 3);
+        let path = Path::new("a.pdf");
+        let fresh = snapshot(1, Duration::ZERO);
+        assert!(!settler.ready(path, fresh));
+        assert!(!settler.ready(path, fresh));
+        assert_eq!(settler.waiting(), vec![path.to_path_buf()]);
+        settler.forget(path);
+        assert!(!settler.is_waiting());
+
+        let mut settler = Settler::new(Duration::ZERO, 3);
+        let fresh = Snapshot {
+            size: 1,
+            modified: None,
+        };
+        assert!(!settler.ready(path, fresh));
+        assert!(settler.ready(path, fresh));
+        assert!(!settler.is_waiting());
+    }
+
+    #[test]
+    fn failures_are_reported_once_and_parked_until_the_file_changes() {
+        let mut settler = Settler::new(Duration::from_secs(10), 2);
+        let path = Path::new("a.pdf");
+        let old = snapshot(1, Duration::from_secs(60));
+        assert!(!settler.failed(path, old));
+        assert!(settler.is_waiting());
+        assert!(!settler.ready(path, old), "retry is not due yet");
+        assert!(!settler.failed(path, old));
+        assert!(settler.failed(path, old));
+        assert!(!settler.failed(path, old), "reported only once");
+
+        assert!(!settler.is_waiting());
+        assert!(settler.waiting().is_empty());
+        assert!(!settler.ready(path, old));
+
+        assert!(settler.ready(path, snapshot(2, Duration::from_secs(60))));
+        assert!(!settler.is_waiting());
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let mut settler = Settler::new(Duration::from_secs(10), 2);
+        let path = Path::new("a.pdf");
+        settler.failed(path, snapshot(1, Duration::from_secs(60)));
+        settler.succeeded(path);
+        assert!(!settler.is_waiting());
+    }
+}
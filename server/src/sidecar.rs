@@ -0,0 +1,74 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::persist::write_atomic;
+use crate::stats::DiffStats;
+
+/// Metadata written as `<diff>.json` next to every generated diff. Events
+/// are built from it, so nothing has to be recovered from the diff's
+/// filename.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffSidecar {
+    /// Source path relative to the location, without extension.
+    pub title: String,
+    pub created: DateTime<Utc>,
+    /// The compared file, relative to the location's `current_path`.
+    pub source: PathBuf,
+    /// `current_path` of the location the file belongs to.
+    pub location: PathBuf,
+    /// Hex SHA-256 of the previous version, `None` for a new file.
+    pub old_hash: Option<String>,
+    /// Hex SHA-256 of the version the diff was generated from; for a
+    /// deletion, of the last known version.
+    pub new_hash: String,
+    pub stats: DiffStats,
+    #[serde(default)]
+    pub change: DocumentChange,
+    /// Previous path relative to `current_path`, for renamed and moved
+    /// files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_from: Option<PathBuf>,
+}
+
+/// What happened to the source document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentChange {
+    /// New or changed; the PDF is the diff.
+    #[default]
+    Modified,
+    /// Removed from `current_path`; the PDF is the last known version.
+    Deleted,
+    /// Renamed or moved without changing its content. Only the sidecar is
+    /// written; the event shows the document from the version store.
+    /// Renamed and edited documents are `Modified` with `renamed_from` set.
+    Renamed,
+}
+
+impl DiffSidecar {
+    /// Write the sidecar next to the diff at `diff_path`.
+    pub async fn save(&self, diff_path: &Path) -> io::Result<()> {
+        let (path, bytes) = (sidecar_path(diff_path), serde_json::to_vec(self)?);
+        tokio::task::spawn_blocking(move || write_atomic(&path, &bytes)).await?
+    }
+
+    /// Sidecar of the diff at `diff_path`, `None` for diffs written before
+    /// sidecars existed.
+    pub async fn load(diff_path: &Path) -> Option<Self> {
+        let bytes = tokio::fs::read(sidecar_path(diff_path)).await.ok()?;
+        match serde_json::from_slice(&bytes) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                tracing::warn!(path = %diff_path.display(), "unreadable diff sidecar: {}", e);
+                None
+            }
+        }
+    }
+}
+
+fn sidecar_path(diff_path: &Path) -> PathBuf {
+    diff_path.with_extension("json")
+}
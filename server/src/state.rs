@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::persist::PendingWrite;
+use crate::settle::Snapshot;
+
+/// Size, modification time and content hash of a file as it was last
+/// processed, i.e. the version its `last_path` copy holds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Hex SHA-256 of the file's bytes.
+    pub hash: String,
+    /// This content crashed or hung a pdf worker, so the file is not
+    /// processed again until it changes.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quarantined: bool,
+}
+
+impl FileState {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            size: self.size,
+            modified: self.modified,
+        }
+    }
+
+    /// Whether size and mtime still match, in which case the content is
+    /// assumed unchanged without hashing it.
+    pub fn matches_metadata(&self, size: u64, modified: Option<SystemTime>) -> bool {
+        self.size == size && modified.is_some() && self.modified == modified
+    }
+}
+
+/// Last processed state of every tracked file, persisted as JSON between
+/// runs and keyed by the file's path in `current_path`.
+#[derive(Debug)]
+pub struct StateIndex {
+    path: PathBuf,
+    entries: HashMap<String, FileState>,
+    dirty: bool,
+}
+
+impl StateIndex {
+    /// Load the index at `path`, starting empty if it is missing or
+    /// unreadable.
+    pub async fn load(path: PathBuf) -> Self {
+        let entries = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                tracing::warn!(path = %path.display(), "discarding state index: {}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        StateIndex {
+            path,
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub fn get(&self, file: &Path) -> Option<FileState> {
+        self.entries.get(&key(file)).cloned()
+    }
+
+    pub fn insert(&mut self, file: &Path, state: FileState) {
+        if self.entries.get(&key(file)) != Some(&state) {
+            self.entries.insert(key(file), state);
+            self.dirty = true;
+        }
+    }
+
+    pub fn quarantined(&self) -> Vec<PathBuf> {
+        self.entries
+            .iter()
+            .filter(|(_, state)| state.quarantined)
+            .map(|(file, _)| PathBuf::from(file))
+            .collect()
+    }
+
+    pub fn remove(&mut self, file: &Path) {
+        if self.entries.remove(&key(file)).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// The serialized index if anything changed since the last call, for
+    /// writing once the lock is released.
+    pub fn pending_write(&mut self) -> io::Result<Option<PendingWrite>> {
+        if !self.dirty {
+            return Ok(None);
+        }
+        let bytes = serde_json::to_vec(&self.entries)?;
+        self.dirty = false;
+        Ok(Some(PendingWrite::new(self.path.clone(), bytes)))
+    }
+
+    /// Mark the index as changed again after its `PendingWrite` failed.
+    pub fn write_failed(&mut self) {
+        self.dirty = true;
+    }
+}
+
+fn key(file: &Path) -> String {
+    file.to_string_lossy().into_owned()
+}
@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+
+use crate::pdf::{Comparison, DifferenceSegments};
+
+/// What happened to a single page between the two versions.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PageChange {
+    Changed,
+    Added,
+    Removed,
+    Moved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageStats {
+    /// 1-based page number in the new version, `None` for removed pages.
+    pub page: Option<u16>,
+    /// 1-based page number in the previous version, `None` for added pages.
+    pub old_page: Option<u16>,
+    pub change: PageChange,
+    /// Share of the page covered by changed regions, in percent.
+    pub changed_area: f64,
+    /// Runs of words added to the page, when `text_diff` is enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub text_added: Vec<String>,
+    /// Runs of words removed from the page, when `text_diff` is enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub text_removed: Vec<String>,
+}
+
+/// Summary of one diff, small enough to ship with every event so clients
+/// can describe a change without downloading the PDF.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiffStats {
+    /// Page count of the new version.
+    pub total_pages: u16,
+    pub pages_changed: u16,
+    pub pages_added: u16,
+    pub pages_removed: u16,
+    pub pages_moved: u16,
+    /// One entry per page that is not identical, in diff order.
+    pub pages: Vec<PageStats>,
+}
+
+impl DiffStats {
+    pub fn from_comparisons(comparisons: &[Comparison]) -> Self {
+        let mut stats = DiffStats::default();
+        for (index, comparison) in comparisons.iter().enumerate() {
+            let page = Some(index as u16 + 1);
+            let entry = match comparison {
+                Comparison::Identical => None,
+                Comparison::Different(segments) => Some(PageStats {
+                    page,
+                    old_page: segments.old_page.map(|j| j + 1),
+                    change: PageChange::Changed,
+                    changed_area: changed_area(segments),
+                    text_added: Vec::new(),
+                    text_removed: Vec::new(),
+                }),
+                Comparison::Inserted(_) => Some(PageStats {
+                    page,
+                    old_page: None,
+                    change: PageChange::Added,
+                    changed_area: 100.,
+                    text_added: Vec::new(),
+                    text_removed: Vec::new(),
+                }),
+                Comparison::Moved { from, changes } => Some(PageStats {
+                    page,
+                    old_page: Some(from + 1),
+                    change: PageChange::Moved,
+                    changed_area: changes.as_ref().map(changed_area).unwrap_or(0.),
+                    text_added: Vec::new(),
+                    text_removed: Vec::new(),
+                }),
+                Comparison::Deleted(old_index) => Some(PageStats {
+                    page: None,
+                    old_page: Some(old_index + 1),
+                    change: PageChange::Removed,
+                    changed_area: 100.,
+                    text_added: Vec::new(),
+                    text_removed: Vec::new(),
+                }),
+            };
+            if !matches!(comparison, Comparison::Deleted(_)) {
+                stats.total_pages += 1;
+            }
+            let Some(mut entry) = entry else {
+                continue;
+            };
+            let segments = match comparison {
+                Comparison::Different(segments) | Comparison::Inserted(segments) => Some(segments),
+                Comparison::Moved { changes, .. } => changes.as_ref(),
+                _ => None,
+            };
+            if let Some(text) = segments.and_then(|s| s.text.as_ref()) {
+                entry.text_added = text.inserted.iter().map(|r| r.text.clone()).collect();
+                entry.text_removed = text.deleted.iter().map(|r| r.text.clone()).collect();
+            }
+            match entry.change {
+                PageChange::Changed => stats.pages_changed += 1,
+                PageChange::Added => stats.pages_added += 1,
+                PageChange::Removed => stats.pages_removed += 1,
+                PageChange::Moved => stats.pages_moved += 1,
+            }
+            stats.pages.push(entry);
+        }
+        stats
+    }
+}
+
+/// Sum of the region areas in percent of the page, capped at 100. Regions
+/// come from separate connected components, so overlap is rare.
+fn changed_area(segments: &DifferenceSegments) -> f64 {
+    let area: f64 = segments
+        .regions
+        .iter()
+        .map(|r| (r.right - r.left).max(0.) * (r.bottom - r.top).max(0.))
+        .sum();
+    (area * 100.).min(100.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::Rect;
+    use crate::text::{TextDiff, TextRun};
+
+    fn rect(left: f64, top: f64, right: f64, bottom: f64) -> Rect {
+        Rect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    fn run(text: &str) -> TextRun {
+        TextRun {
+            text: text.to_string(),
+            bounds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn identical_pages_only_count_towards_the_total() {
+        let stats = DiffStats::from_comparisons(&[Comparison::Identical, Comparison::Identical]);
+        assert_eq!(stats.total_pages, 2);
+        assert_eq!(
+            (
+                stats.pages_changed,
+                stats.pages_added,
+                stats.pages_removed,
+                stats.pages_moved
+            ),
+            (0, 0, 0, 0)
+        );
+        assert!(stats.pages.is_empty());
+    }
+
+    #[test]
+    fn changed_page_carries_area_and_text() {
+        let changed = DifferenceSegments {
+            regions: vec![rect(0., 0., 0.5, 0.5), rect(0.5, 0.5, 0.75, 0.75)],
+            text: Some(TextDiff {
+                inserted: vec![run("new words")],
+                deleted: vec![run("old")],
+            }),
+            old_page: Some(2),
+            ..DifferenceSegments::default()
+        };
+        let stats =
+            DiffStats::from_comparisons(&[Comparison::Identical, Comparison::Different(changed)]);
+        assert_eq!((stats.total_pages, stats.pages_changed), (2, 1));
+        let page = &stats.pages[0];
+        assert_eq!((page.page, page.old_page), (Some(2), Some(3)));
+        assert_eq!(page.change, PageChange::Changed);
+        assert_eq!(page.changed_area, 31.25);
+        assert_eq!(page.text_added, vec!["new words"]);
+        assert_eq!(page.text_removed, vec!["old"]);
+    }
+
+    #[test]
+    fn inserted_moved_and_deleted_pages() {
+        let inserted = DifferenceSegments {
+            regions: vec![Rect::FULL],
+            text: Some(TextDiff {
+                inserted: vec![run("hello")],
+                deleted: Vec::new(),
+            }),
+            ..DifferenceSegments::default()
+        };
+        let stats = DiffStats::from_comparisons(&[
+            Comparison::Moved {
+                from: 1,
+                changes: None,
+            },
+            Comparison::Inserted(inserted),
+            Comparison::Deleted(0),
+        ]);
+        assert_eq!(stats.total_pages, 2);
+        assert_eq!(
+            (stats.pages_added, stats.pages_removed, stats.pages_moved),
+            (1, 1, 1)
+        );
+        let summary: Vec<_> = stats
+            .pages
+            .iter()
+            .map(|p| (p.page, p.old_page, p.change, p.changed_area))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(1), Some(2), PageChange::Moved, 0.),
+                (Some(2), None, PageChange::Added, 100.),
+                (None, Some(1), PageChange::Removed, 100.),
+            ]
+        );
+        assert_eq!(stats.pages[1].text_added, vec!["hello"]);
+    }
+
+    #[test]
+    fn moved_page_with_changes_counts_their_area() {
+        let changes = DifferenceSegments {
+            regions: vec![rect(0., 0., 1., 0.25)],
+            old_page: Some(0),
+            ..DifferenceSegments::default()
+        };
+        let stats = DiffStats::from_comparisons(&[
+            Comparison::Identical,
+            Comparison::Moved {
+                from: 0,
+                changes: Some(changes),
+            },
+        ]);
+        assert_eq!(stats.pages_moved, 1);
+        assert_eq!(stats.pages[0].changed_area, 25.);
+    }
+
+    #[test]
+    fn overlapping_regions_are_capped_at_the_whole_page() {
+        let changed = DifferenceSegments {
+            regions: vec![Rect::FULL, Rect::FULL],
+            ..DifferenceSegments::default()
+        };
+        let stats = DiffStats::from_comparisons(&[Comparison::Different(changed)]);
+        assert_eq!(stats.pages[0].changed_area, 100.);
+    }
+}
@@ -0,0 +1,215 @@
+use pdfium_render::prelude::*;
+
+use crate::pdf::Rect;
+
+/// A single whitespace-delimited word extracted from a page, with its
+/// bounds as fractions of the page size (origin top-left).
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub text: String,
+    pub bounds: Rect,
+}
+
+/// A run of consecutive words that were inserted or deleted. Bounds are
+/// merged per line so a run spanning a line break yields two rectangles.
+#[derive(Debug, Clone)]
+pub struct TextRun {
+    pub text: String,
+    pub bounds: Vec<Rect>,
+}
+
+/// Word-level text difference between a new page and its matched old page.
+/// `inserted` bounds refer to the new page, `deleted` bounds to the old one.
+#[derive(Debug, Clone, Default)]
+pub struct TextDiff {
+    pub inserted: Vec<TextRun>,
+    pub deleted: Vec<TextRun>,
+}
+
+impl TextDiff {
+    pub fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.deleted.is_empty()
+    }
+
+    /// One line per non-empty side, e.g. `Added: "foo bar"`.
+    pub fn summary(&self) -> String {
+        [("Added", &self.inserted), ("Removed", &self.deleted)]
+            .into_iter()
+            .filter(|(_, runs)| !runs.is_empty())
+            .map(|(label, runs)| {
+                let runs: Vec<String> = runs.iter().map(|r| format!("\"{}\"", r.text)).collect();
+                format!("{}: {}", label, runs.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Pull the words of `page` through pdfium's text API.
+pub fn extract_words(page: &PdfPage) -> Result<Vec<Word>, PdfiumError> {
+    let width = page.width().value as f64;
+    let height = page.height().value as f64;
+    let text = page.text()?;
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    for c in text.chars().iter() {
+        let ch = c.unicode_char();
+        if ch.is_none_or(char::is_whitespace) {
+            if let Some(word) = current.take() {
+                words.push(word);
+            }
+            continue;
+        }
+        let Ok(bounds) = c.loose_bounds() else {
+            continue;
+        };
+        let bounds = Rect::from_pdf_rect(&bounds, width, height);
+        match &mut current {
+            Some(word) => {
+                word.text.extend(ch);
+                word.bounds = word.bounds.union(&bounds);
+            }
+            None => {
+                current = Some(Word {
+                    text: ch.map(String::from).unwrap_or_default(),
+                    bounds,
+                })
+            }
+        }
+    }
+    if let Some(word) = current {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Diff two word sequences with an LCS table and group the unmatched words
+/// into inserted (`new` only) and deleted (`old` only) runs.
+pub fn diff_words(new: &[Word], old: &[Word]) -> TextDiff {
+    let (n, m) = (new.len(), old.len());
+    // lcs[i][j] = length of the LCS of new[i..] and old[j..]
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if new[i].text == old[j].text {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = TextDiff::default();
+    let mut inserted = RunBuilder::default();
+    let mut deleted = RunBuilder::default();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && new[i].text == old[j].text {
+            inserted.flush(&mut diff.inserted);
+            deleted.flush(&mut diff.deleted);
+            i += 1;
+            j += 1;
+        } else if j >= m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            inserted.push(&new[i]);
+            i += 1;
+        } else {
+            deleted.push(&old[j]);
+            j += 1;
+        }
+    }
+    inserted.flush(&mut diff.inserted);
+    deleted.flush(&mut diff.deleted);
+    diff
+}
+
+#[derive(Default)]
+struct RunBuilder {
+    words: Vec<String>,
+    bounds: Vec<Rect>,
+}
+
+impl RunBuilder {
+    fn push(&mut self, word: &Word) {
+        self.words.push(word.text.clone());
+        match self.bounds.last_mut() {
+            Some(last) if last.same_line(&word.bounds) => *last = last.union(&word.bounds),
+            _ => self.bounds.push(word.bounds),
+        }
+    }
+
+    fn flush(&mut self, into: &mut Vec<TextRun>) {
+        if self.words.is_empty() {
+            return;
+        }
+        into.push(TextRun {
+            text: self.words.join(" "),
+            bounds: std::mem::take(&mut self.bounds),
+        });
+        self.words.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Words laid out left to right, one line per `|`.
+    fn words(text: &str) -> Vec<Word> {
+        let mut words = Vec::new();
+        for (line, text) in text.split('|').enumerate() {
+            let top = line as f64 * 0.1;
+            for (column, word) in text.split_whitespace().enumerate() {
+                let left = column as f64 * 0.1;
+                words.push(Word {
+                    text: word.to_string(),
+                    bounds: Rect {
+                        left,
+                        top,
+                        right: left + 0.08,
+                        bottom: top + 0.05,
+                    },
+                });
+            }
+        }
+        words
+    }
+
+    fn texts(runs: &[TextRun]) -> Vec<&str> {
+        runs.iter().map(|r| r.text.as_str()).collect()
+    }
+
+    #[test]
+    fn unchanged_text() {
+        let diff = diff_words(&words("a b c"), &words("a b c"));
+        assert!(diff.is_empty());
+        assert_eq!(diff.summary(), "");
+    }
+
+    #[test]
+    fn insertions_and_deletions_form_runs() {
+        let diff = diff_words(&words("a x y b c"), &words("a b z c"));
+        assert_eq!(texts(&diff.inserted), vec!["x y"]);
+        assert_eq!(texts(&diff.deleted), vec!["z"]);
+        assert_eq!(diff.summary(), "Added: \"x y\"\nRemoved: \"z\"");
+    }
+
+    #[test]
+    fn runs_across_line_breaks_keep_one_rect_per_line() {
+        let diff = diff_words(&words("a x y|z b"), &words("a b"));
+        assert_eq!(texts(&diff.inserted), vec!["x y z"]);
+        assert_eq!(diff.inserted[0].bounds.len(), 2);
+    }
+
+    #[test]
+    fn empty_pages() {
+        let diff = diff_words(&words("a b"), &[]);
+        assert_eq!(texts(&diff.inserted), vec!["a b"]);
+        assert!(diff.deleted.is_empty());
+
+        let diff = diff_words(&[], &words("a b"));
+        assert!(diff.inserted.is_empty());
+        assert_eq!(texts(&diff.deleted), vec!["a b"]);
+
+        assert!(diff_words(&[], &[]).is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::persist::PendingWrite;
+
+/// One observed version of a document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    /// Hex SHA-256 of the content, which is stored as `objects/<hash>.pdf`.
+    pub hash: String,
+    pub observed: DateTime<Utc>,
+    pub size: u64,
+}
+
+/// Every revision of every document of a location. Contents are stored
+/// once per hash, so reverting a file or keeping identical copies in
+/// several folders costs no extra space.
+#[derive(Debug)]
+pub struct VersionStore {
+    root: PathBuf,
+    /// Revisions per document path (relative to `current_path`), oldest
+    /// first.
+    index: HashMap<String, Vec<Revision>>,
+    dirty: bool,
+}
+
+impl VersionStore {
+    /// Open the store at `root`, starting empty if there is none yet. An
+    /// unreadable index is moved to `index.json.corrupt` first, so the next
+    /// save does not overwrite the history it still holds.
+    pub async fn open(root: PathBuf) -> io::Result<Self> {
+        let index_path = root.join("index.json");
+        let index = match tokio::fs::read(&index_path).await {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(index) => index,
+                Err(e) => {
+                    let corrupt = root.join("index.json.corrupt");
+                    tracing::warn!(
+                        path = %index_path.display(),
+                        "unreadable version index, moving it to {}: {}",
+                        corrupt.display(),
+                        e
+                    );
+                    tokio::fs::rename(&index_path, &corrupt).await?;
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(VersionStore {
+            root,
+            index,
+            dirty: false,
+        })
+    }
+
+    pub fn revisions(&self, document: &Path) -> &[Revision] {
+        self.index
+            .get(&key(document))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The revision of `document` that was current at `time`.
+    pub fn at(&self, document: &Path, time: DateTime<Utc>) -> Option<&Revision> {
+        self.revisions(document)
+            .iter()
+            .rev()
+            .find(|r| r.observed <= time)
+    }
+
+    pub fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join("objects").join(format!("{}.pdf", hash))
+    }
+
+    /// Where the diff between two stored contents is cached. `settings`
+    /// identifies the settings it is made with (see `DiffSettings::key`),
+    /// so changing them does not serve diffs made with the old ones.
+    pub fn diff_path(&self, old_hash: &str, new_hash: &str, settings: &str) -> PathBuf {
+        self.root
+            .join("diffs")
+            .join(format!("{}.{}.{}.pdf", old_hash, new_hash, settings))
+    }
+
+    /// Whether the content with `hash` already is the newest revision of
+    /// `document`.
+    pub fn is_current(&self, document: &Path, hash: &str) -> bool {
+        self.revisions(document)
+            .last()
+            .is_some_and(|r| r.hash == hash)
+    }
+
+    /// Add `revision`, whose content is already stored (see
+    /// `store_object`), as the newest revision of `document`.
+    pub fn push(&mut self, document: &Path, revision: Revision) {
+        self.index.entry(key(document)).or_default().push(revision);
+        self.dirty = true;
+    }
+
+    /// Continue the history of `from` under `to` after a rename.
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        let Some(revisions) = self.index.remove(&key(from)) else {
+            return;
+        };
+        let history = self.index.entry(key(to)).or_default();
+        let newer = std::mem::replace(history, revisions);
+        history.extend(newer);
+        self.dirty = true;
+    }
+
+    /// The serialized index if anything changed since the last call, for
+    /// writing once the lock is released.
+    pub fn pending_write(&mut self) -> io::Result<Option<PendingWrite>> {
+        if !self.dirty {
+            return Ok(None);
+        }
+        let bytes = serde_json::to_vec(&self.index)?;
+        self.dirty = false;
+        Ok(Some(PendingWrite::new(self.root.join("index.json"), bytes)))
+    }
+
+    /// Mark the index as changed again after its `PendingWrite` failed.
+    pub fn write_failed(&mut self) {
+        self.dirty = true;
+    }
+}
+
+/// Copy `source` to `object` (a `VersionStore::object_path`) unless it is
+/// stored already, and return its size. Runs on a blocking thread so large
+/// copies neither stall the executor nor hold the store's lock.
+pub async fn store_object(
+    object: PathBuf,
+    source: PathBuf,
+    observed: DateTime<Utc>,
+) -> io::Result<u64> {
+    tokio::task::spawn_blocking(move || {
+        if !object.exists() {
+            if let Some(parent) = object.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Copy under a temporary name so a crash never leaves a
+            // truncated object behind its final name.
+            let partial = object.with_extension("partial");
+            std::fs::copy(&source, &partial)?;
+            std::fs::rename(&partial, &object)?;
+            // Diffs label changes with the old file's mtime.
+            std::fs::File::options()
+                .write(true)
+                .open(&object)?
+                .set_modified(observed.into())?;
+        }
+        Ok(std::fs::metadata(&object)?.len())
+    })
+    .await?
+}
+
+fn key(document: &Path) -> String {
+    document.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::write_pending;
+
+    fn temp_root() -> PathBuf {
+        std::env::temp_dir().join(format!("versions-test-{:016x}", rand::random::<u64>()))
+    }
+
+    /// An empty store; nothing is written unless it is saved.
+    fn store() -> VersionStore {
+        VersionStore {
+            root: temp_root(),
+            index: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(seconds, 0).unwrap()
+    }
+
+    fn revision(hash: &str, observed: i64) -> Revision {
+        Revision {
+            hash: hash.to_string(),
+            observed: at(observed),
+            size: 1,
+        }
+    }
+
+    fn hash_at(store: &VersionStore, document: &str, time: i64) -> Option<String> {
+        store
+            .at(Path::new(document), at(time))
+            .map(|r| r.hash.clone())
+    }
+
+    #[test]
+    fn lookup_finds_the_revision_current_at_a_time() {
+        let mut store = store();
+        let doc = Path::new("notes.pdf");
+        store.push(doc, revision("a", 100));
+        store.push(doc, revision("b", 200));
+        assert_eq!(hash_at(&store, "notes.pdf", 50), None);
+        assert_eq!(hash_at(&store, "notes.pdf", 100).as_deref(), Some("a"));
+        assert_eq!(hash_at(&store, "notes.pdf", 150).as_deref(), Some("a"));
+        assert_eq!(hash_at(&store, "notes.pdf", 200).as_deref(), Some("b"));
+        assert_eq!(hash_at(&store, "notes.pdf", 300).as_deref(), Some("b"));
+        assert_eq!(hash_at(&store, "other.pdf", 300), None);
+    }
+
+    #[test]
+    fn is_current_follows_the_newest_revision() {
+        let mut store = store();
+        let doc = Path::new("notes.pdf");
+        assert!(!store.is_current(doc, "a"));
+        store.push(doc, revision("a", 100));
+        assert!(store.is_current(doc, "a"));
+        store.push(doc, revision("b", 200));
+        assert!(!store.is_current(doc, "a"));
+        assert!(store.is_current(doc, "b"));
+    }
+
+    #[test]
+    fn rename_carries_the_history_over() {
+        let mut store = store();
+        let (from, to) = (Path::new("old/notes.pdf"), Path::new("new/notes.pdf"));
+        store.push(from, revision("a", 100));
+        store.push(from, revision("b", 200));
+        store.rename(from, to);
+        assert!(store.revisions(from).is_empty());
+        assert_eq!(hash_at(&store, "new/notes.pdf", 150).as_deref(), Some("a"));
+        assert!(store.is_current(to, "b"));
+    }
+
+    #[test]
+    fn rename_onto_a_newer_history_keeps_it_last() {
+        let mut store = store();
+        let (from, to) = (Path::new("a.pdf"), Path::new("b.pdf"));
+        store.push(from, revision("a", 100));
+        store.push(to, revision("b", 200));
+        store.rename(from, to);
+        let hashes: Vec<&str> = store
+            .revisions(to)
+            .iter()
+            .map(|r| r.hash.as_str())
+            .collect();
+        assert_eq!(hashes, vec!["a", "b"]);
+        assert!(store.is_current(to, "b"));
+    }
+
+    #[tokio::test]
+    async fn saved_index_is_opened_again() {
+        let mut store = store();
+        store.push(Path::new("notes.pdf"), revision("a", 100));
+        write_pending(store.pending_write()).await.unwrap();
+
+        let store = VersionStore::open(store.root.clone()).await.unwrap();
+        assert!(store.is_current(Path::new("notes.pdf"), "a"));
+        std::fs::remove_dir_all(store.root()).ok();
+    }
+
+    #[tokio::test]
+    async fn unreadable_index_is_kept_aside() {
+        let root = temp_root();
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.json"), "{\"notes.pdf\": [").unwrap();
+
+        let store = VersionStore::open(root.clone()).await.unwrap();
+        assert!(store.revisions(Path::new("notes.pdf")).is_empty());
+        assert!(!root.join("index.json").exists());
+        assert_eq!(
+            std::fs::read_to_string(root.join("index.json.corrupt")).unwrap(),
+            "{\"notes.pdf\": ["
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+#[derive(Debug, Default)]
+struct Queue {
+    paths: HashSet<PathBuf>,
+    /// Set when events may have been lost (e.g. the kernel queue
+    /// overflowed), so only a full rescan can be trusted.
+    overflowed: bool,
+}
+
+/// Recursive inotify watch on a location's `current_path` that queues the
+/// paths of created, modified and removed entries until they are drained.
+pub struct LocationWatcher {
+    // Dropping the watcher stops the watch.
+    _watcher: RecommendedWatcher,
+    queue: Arc<Mutex<Queue>>,
+}
+
+/// What changed below the watched root since the last drain.
+pub enum WatchedChanges {
+    Paths(Vec<PathBuf>),
+    /// Events were lost; the whole tree has to be rescanned.
+    Overflowed,
+}
+
+impl LocationWatcher {
+    pub fn new(root: &Path) -> notify::Result<Self> {
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let handler_queue = queue.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            let mut queue = handler_queue.lock().unwrap_or_else(|e| e.into_inner());
+            match res {
+                Ok(event) if event.need_rescan() => queue.overflowed = true,
+                Ok(event) => {
+                    if matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    ) {
+                        queue.paths.extend(event.paths);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("file watcher: {}", e);
+                    queue.overflowed = true;
+                }
+            }
+        })?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        Ok(LocationWatcher {
+            _watcher: watcher,
+            queue,
+        })
+    }
+
+    /// Take everything queued since the last call.
+    pub fn drain(&self) -> WatchedChanges {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let queue = std::mem::take(&mut *queue);
+        if queue.overflowed {
+            return WatchedChanges::Overflowed;
+        }
+        WatchedChanges::Paths(queue.paths.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+impl LocationWatcher {
+    /// Queue `path` as if an event had reported it.
+    pub fn queue(&self, path: PathBuf) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.paths.insert(path);
+    }
+
+    /// Mark events as lost, as an overflowed kernel queue would.
+    pub fn overflow(&self) {
+        self.queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .overflowed = true;
+    }
+}
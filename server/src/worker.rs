@@ -0,0 +1,531 @@
+//! Crash isolation for pdfium. A malformed PDF can crash or hang the
+//! library, so comparisons and diffs run in child processes (this binary
+//! started with `worker`) that read one JSON job per line from stdin and
+//! answer with one JSON reply per line on stdout. A worker that dies or
+//! exceeds its timeout is replaced and the file it was working on is
+//! quarantined.
+
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pdfium_render::prelude::Pdfium;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::align::PageFingerprint;
+use crate::cache::{file_hash, hex_sha256, FingerprintCache};
+use crate::files::FileManagerError;
+use crate::pdf::{
+    get_pdfium, Comparison, ComparisonOptions, ContextOptions, DiffLayout, PDFComparison, PDFEditor,
+};
+use crate::stats::DiffStats;
+
+/// Worker settings, read from `[config.worker]`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorkerOptions {
+    /// Run pdfium in worker processes. When disabled it runs inside the
+    /// plugin, where a crashing PDF takes the whole plugin down.
+    #[serde(default = "default_isolate")]
+    pub isolate: bool,
+    /// Seconds a single comparison may take before its worker is killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Memory (data segment) limit of each worker in MiB, 0 for none.
+    #[serde(default = "default_memory_limit")]
+    pub memory_limit: u64,
+}
+
+fn default_isolate() -> bool {
+    true
+}
+
+fn default_timeout() -> u64 {
+    600
+}
+
+fn default_memory_limit() -> u64 {
+    4096
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        WorkerOptions {
+            isolate: default_isolate(),
+            timeout: default_timeout(),
+            memory_limit: default_memory_limit(),
+        }
+    }
+}
+
+/// How a location compares and marks documents; sent with every job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffSettings {
+    pub comparison: ComparisonOptions,
+    pub layout: DiffLayout,
+    pub context: ContextOptions,
+}
+
+impl DiffSettings {
+    /// Short hash of the settings, for telling apart diffs made with
+    /// different ones.
+    pub fn key(&self) -> String {
+        let json = serde_json::to_vec(self).expect("diff settings serialize");
+        hex_sha256(&json)[..16].to_string()
+    }
+}
+
+/// Compare `new` with `old` and write the diff to `out`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffJob {
+    pub new: PathBuf,
+    pub old: PathBuf,
+    pub out: PathBuf,
+    /// Write the diff even if every page is identical.
+    pub force: bool,
+    pub settings: DiffSettings,
+    /// Cached fingerprints of `new` and `old` by content hash. Worker
+    /// processes only load the cache file when they start, so they would
+    /// miss whatever was computed since.
+    #[serde(default)]
+    pub fingerprints: Vec<(String, Vec<PageFingerprint>)>,
+}
+
+/// Work for `run_job`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Job {
+    Diff(DiffJob),
+    /// Fingerprint every page of the file into the cache.
+    Fingerprint(PathBuf),
+}
+
+impl Job {
+    /// The document the job is about.
+    fn path(&self) -> &Path {
+        match self {
+            Job::Diff(job) => &job.new,
+            Job::Fingerprint(path) => path,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiffReply {
+    result: Result<Option<DiffStats>, String>,
+    /// Fingerprints the worker computed, to be cached by the plugin.
+    fingerprints: Vec<(String, Vec<PageFingerprint>)>,
+    /// Hashes the worker found in its cache, to be marked as used.
+    used: Vec<String>,
+}
+
+/// Passed to the worker as its second argument.
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkerConfig {
+    pdfium_path: Option<PathBuf>,
+    fingerprint_cache_path: PathBuf,
+    /// In bytes.
+    memory_limit: Option<u64>,
+}
+
+/// Run `job` in this process. For a diff, returns the stats of the written
+/// diff, or `None` if the documents render the same and `force` is not
+/// set. Fingerprinting only fills `cache` and always returns `None`.
+pub fn run_job(
+    pdfium: Arc<Pdfium>,
+    cache: Arc<Mutex<FingerprintCache>>,
+    job: &Job,
+) -> Result<Option<DiffStats>, FileManagerError> {
+    let job = match job {
+        Job::Diff(job) => job,
+        Job::Fingerprint(path) => {
+            let pages = PDFComparison::new(pdfium, ComparisonOptions::default(), cache.clone())
+                .fingerprint_file(path)?;
+            // Also when they were cached, so a worker process hands them
+            // back to the plugin, which asked because it has none.
+            cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(file_hash(path)?, pages);
+            return Ok(None);
+        }
+    };
+    let settings = &job.settings;
+    let comparisons = PDFComparison::new(pdfium.clone(), settings.comparison.clone(), cache)
+        .with_overlays(matches!(settings.layout, DiffLayout::Overlay))
+        .compare_pdfs(&job.new, &job.old)?;
+    if !job.force && comparisons.iter().all(Comparison::is_identical) {
+        return Ok(None);
+    }
+    if let Some(parent) = job.out.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    PDFEditor::new(pdfium, settings.layout, settings.context).mark_differences(
+        &job.new,
+        &job.old,
+        &comparisons,
+        &job.out,
+    )?;
+    Ok(Some(DiffStats::from_comparisons(&comparisons)))
+}
+
+/// Entry point of the `worker` subcommand.
+pub async fn run_worker(config: &str) -> anyhow::Result<()> {
+    let config: WorkerConfig = serde_json::from_str(config)?;
+    if let Some(bytes) = config.memory_limit {
+        limit_memory(bytes)?;
+    }
+    // Keep answering if pdfium is missing, so the plugin does not mistake
+    // the failure for a crash caused by the file.
+    let pdfium = get_pdfium(config.pdfium_path.as_deref())
+        .map(Arc::new)
+        .map_err(|e| format!("unable to load pdfium: {}", e));
+    let cache = Arc::new(Mutex::new(
+        FingerprintCache::load(config.fingerprint_cache_path).await,
+    ));
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let job: Job = serde_json::from_str(&line?)?;
+        if let Job::Diff(diff) = &job {
+            let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+            for (hash, pages) in diff.fingerprints.iter().cloned() {
+                cache.seed(hash, pages);
+            }
+        }
+        let result = match &pdfium {
+            Ok(pdfium) => run_job(pdfium.clone(), cache.clone(), &job).map_err(|e| e.to_string()),
+            Err(e) => Err(e.clone()),
+        };
+        let (fingerprints, used) = {
+            let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+            (cache.take_inserted(), cache.take_used())
+        };
+        serde_json::to_writer(
+            &mut stdout,
+            &DiffReply {
+                result,
+                fingerprints,
+                used,
+            },
+        )?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn limit_memory(bytes: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: bytes as libc::rlim_t,
+        rlim_max: bytes as libc::rlim_t,
+    };
+    // SAFETY: `setrlimit` only reads the struct passed to it.
+    if unsafe { libc::setrlimit(libc::RLIMIT_DATA, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn limit_memory(_bytes: u64) -> io::Result<()> {
+    Ok(())
+}
+
+struct WorkerProcess {
+    // Killed when dropped.
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// Why handing a job to a worker failed.
+enum ExchangeError {
+    /// The worker was gone before it got the job, e.g. it died while idle.
+    NotSent(String),
+    /// The worker crashed, hung or replied garbage while on the job.
+    Failed(String),
+}
+
+/// Hands jobs to worker processes, one per slot, starting them on demand
+/// and replacing those that crash or time out.
+pub struct Supervisor {
+    config: String,
+    /// Program and leading arguments starting a worker, followed by the
+    /// config. This binary's `worker` subcommand if `None`.
+    command: Option<(PathBuf, Vec<String>)>,
+    timeout: Duration,
+    slots: Vec<tokio::sync::Mutex<Option<WorkerProcess>>>,
+}
+
+impl Supervisor {
+    /// `workers` should match the worker pool, which limits how many jobs
+    /// run at once.
+    pub fn new(
+        options: &WorkerOptions,
+        pdfium_path: Option<PathBuf>,
+        fingerprint_cache_path: PathBuf,
+        workers: usize,
+    ) -> Self {
+        let config = WorkerConfig {
+            pdfium_path,
+            fingerprint_cache_path,
+            memory_limit: (options.memory_limit > 0).then(|| options.memory_limit * 1024 * 1024),
+        };
+        Supervisor {
+            config: serde_json::to_string(&config).expect("worker config serializes"),
+            command: None,
+            timeout: Duration::from_secs(options.timeout),
+            slots: (0..workers.max(1))
+                .map(|_| tokio::sync::Mutex::new(None))
+                .collect(),
+        }
+    }
+
+    /// Run `job` in a worker, add the fingerprints it computed to `cache`
+    /// and refresh those it used. If the worker crashes or times out, the
+    /// error is `Quarantined`. A worker found dead before it got the job is
+    /// replaced and the job tried once more.
+    pub async fn run(
+        &self,
+        job: &Job,
+        cache: &Mutex<FingerprintCache>,
+    ) -> Result<Option<DiffStats>, FileManagerError> {
+        let mut slot = self.free_slot().await;
+        let mut respawned = false;
+        loop {
+            if slot.is_none() {
+                *slot = Some(self.spawn()?);
+            }
+            let worker = slot.as_mut().unwrap();
+            match self.exchange(worker, job).await {
+                Ok(reply) => {
+                    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+                    for (hash, pages) in reply.fingerprints {
+                        cache.insert(hash, pages);
+                    }
+                    for hash in &reply.used {
+                        cache.touch(hash);
+                    }
+                    return reply.result.map_err(FileManagerError::Worker);
+                }
+                Err(ExchangeError::NotSent(reason)) => {
+                    *slot = None;
+                    // Not the file's fault, so never quarantine it for this.
+                    if respawned {
+                        return Err(FileManagerError::Worker(reason));
+                    }
+                    tracing::warn!("idle pdf worker was lost, restarting it: {}", reason);
+                    respawned = true;
+                }
+                Err(ExchangeError::Failed(reason)) => {
+                    tracing::warn!(path = %job.path().display(), "pdf worker failed: {}", reason);
+                    *slot = None;
+                    return Err(FileManagerError::Quarantined(reason));
+                }
+            }
+        }
+    }
+
+    async fn free_slot(&self) -> tokio::sync::MutexGuard<'_, Option<WorkerProcess>> {
+        for slot in &self.slots {
+            if let Ok(guard) = slot.try_lock() {
+                return guard;
+            }
+        }
+        // Only reached if more jobs run at once than there are slots.
+        self.slots[0].lock().await
+    }
+
+    fn spawn(&self) -> io::Result<WorkerProcess> {
+        let mut command = match &self.command {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+            None => {
+                let mut command = Command::new(std::env::current_exe()?);
+                command.arg("worker");
+                command
+            }
+        };
+        let mut child = command
+            .arg(&self.config)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("worker stdin is piped");
+        let stdout = child.stdout.take().expect("worker stdout is piped");
+        Ok(WorkerProcess {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+        })
+    }
+
+    /// Send `job` and wait for the reply. Errors describe why the worker
+    /// has to be replaced.
+    async fn exchange(
+        &self,
+        worker: &mut WorkerProcess,
+        job: &Job,
+    ) -> Result<DiffReply, ExchangeError> {
+        let mut request =
+            serde_json::to_string(job).map_err(|e| ExchangeError::Failed(e.to_string()))?;
+        request.push('\n');
+        if let Ok(Some(status)) = worker.child.try_wait() {
+            return Err(ExchangeError::NotSent(format!(
+                "worker exited ({})",
+                status
+            )));
+        }
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let send = async {
+            worker.stdin.write_all(request.as_bytes()).await?;
+            worker.stdin.flush().await
+        };
+        match tokio::time::timeout_at(deadline, send).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(ExchangeError::NotSent(format!("worker lost: {}", e))),
+            Err(_) => {
+                worker.child.kill().await.ok();
+                return Err(ExchangeError::NotSent(format!(
+                    "not accepting jobs after {}s",
+                    self.timeout.as_secs()
+                )));
+            }
+        }
+        let mut reply = String::new();
+        let failure =
+            match tokio::time::timeout_at(deadline, worker.stdout.read_line(&mut reply)).await {
+                Err(_) => {
+                    worker.child.kill().await.ok();
+                    format!("timed out after {}s", self.timeout.as_secs())
+                }
+                Ok(Ok(_)) if !reply.is_empty() => {
+                    return serde_json::from_str(&reply)
+                        .map_err(|e| ExchangeError::Failed(format!("unreadable reply: {}", e)));
+                }
+                Ok(_) => match worker.child.wait().await {
+                    Ok(status) => format!("worker exited ({})", status),
+                    Err(e) => format!("worker lost: {}", e),
+                },
+            };
+        Err(ExchangeError::Failed(failure))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    const REPLY: &str = r#"{"result":{"Ok":null},"fingerprints":[],"used":[]}"#;
+
+    /// Runs `script` with `sh` as its worker. Every worker started appends
+    /// a line to the returned file.
+    fn supervisor(script: &str, timeout: Duration) -> (Supervisor, PathBuf) {
+        let spawns =
+            std::env::temp_dir().join(format!("worker-test-{:016x}", rand::random::<u64>()));
+        let script = format!("echo >> '{}'; {}", spawns.display(), script);
+        let mut supervisor = Supervisor::new(
+            &WorkerOptions::default(),
+            None,
+            PathBuf::from("fingerprints.json"),
+            1,
+        );
+        supervisor.command = Some((
+            PathBuf::from("sh"),
+            vec!["-c".to_string(), script, "sh".to_string()],
+        ));
+        supervisor.timeout = timeout;
+        (supervisor, spawns)
+    }
+
+    fn spawned(spawns: &Path) -> usize {
+        std::fs::read_to_string(spawns).map_or(0, |s| s.lines().count())
+    }
+
+    async fn cache() -> Mutex<FingerprintCache> {
+        Mutex::new(FingerprintCache::load(PathBuf::from("/nonexistent/fingerprints.json")).await)
+    }
+
+    fn job(path: &str) -> Job {
+        Job::Fingerprint(PathBuf::from(path))
+    }
+
+    #[tokio::test]
+    async fn crashed_worker_quarantines_the_file_and_is_replaced() {
+        let script = format!(
+            "while read line; do case \"$line\" in *crash*) exit 1;; esac; echo '{}'; done",
+            REPLY
+        );
+        let (supervisor, spawns) = supervisor(&script, Duration::from_secs(10));
+        let cache = cache().await;
+
+        assert!(supervisor
+            .run(&job("a.pdf"), &cache)
+            .await
+            .unwrap()
+            .is_none());
+        let crashed = supervisor.run(&job("crash.pdf"), &cache).await;
+        assert!(matches!(crashed, Err(FileManagerError::Quarantined(_))));
+        assert_eq!(spawned(&spawns), 1);
+
+        assert!(supervisor
+            .run(&job("b.pdf"), &cache)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(spawned(&spawns), 2);
+        std::fs::remove_file(&spawns).ok();
+    }
+
+    #[tokio::test]
+    async fn hung_worker_is_killed_after_the_timeout() {
+        let (supervisor, spawns) = supervisor("read line; sleep 30", Duration::from_millis(200));
+        let cache = cache().await;
+
+        match supervisor.run(&job("a.pdf"), &cache).await {
+            Err(FileManagerError::Quarantined(reason)) => assert!(reason.contains("timed out")),
+            other => panic!("expected a quarantine, got {:?}", other),
+        }
+        assert!(supervisor.slots[0].lock().await.is_none());
+        std::fs::remove_file(&spawns).ok();
+    }
+
+    #[tokio::test]
+    async fn worker_lost_while_idle_is_restarted_for_the_job() {
+        // Answers one job, then exits.
+        let script = format!("read line; echo '{}'", REPLY);
+        let (supervisor, spawns) = supervisor(&script, Duration::from_secs(10));
+        let cache = cache().await;
+
+        assert!(supervisor
+            .run(&job("a.pdf"), &cache)
+            .await
+            .unwrap()
+            .is_none());
+        loop {
+            let mut slot = supervisor.slots[0].lock().await;
+            if slot.as_mut().unwrap().child.try_wait().unwrap().is_some() {
+                break;
+            }
+            drop(slot);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(supervisor
+            .run(&job("b.pdf"), &cache)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(spawned(&spawns), 2);
+        std::fs::remove_file(&spawns).ok();
+    }
+}